@@ -7,6 +7,26 @@ fn main() {
     let payload = env::var("MEOWSBI_PAYLOAD").ok();
 
     let payload_rs = Path::new(&out_dir).join("payload_content.rs");
+    let config_rs = Path::new(&out_dir).join("config.rs");
+    let chosen_rs = Path::new(&out_dir).join("chosen_content.rs");
+
+    // Maximum number of harts that get a storage slot, extra harts are parked
+    let hart_max: usize = env::var("MEOWSBI_HART_MAX")
+        .map(|v| v.parse().expect("MEOWSBI_HART_MAX is not a number"))
+        .unwrap_or(8);
+
+    // Space reserved after the relocated FDT for fixups
+    let fdt_slack: usize = env::var("MEOWSBI_FDT_SLACK")
+        .map(|v| v.parse().expect("MEOWSBI_FDT_SLACK is not a number"))
+        .unwrap_or(0x10000);
+
+    fs::write(
+        &config_rs,
+        format!(
+            "const HART_MAX: usize = {};\nconst FDT_SLACK: usize = {};\n",
+            hart_max, fdt_slack
+        ),
+    ).unwrap();
 
     if let Some(payload) = payload {
         fs::write(
@@ -28,6 +48,29 @@ fn main() {
         "#).unwrap();
     }
 
+    // Initrd and kernel command line, written into /chosen at boot
+    let initrd = env::var("MEOWSBI_INITRD").ok();
+    let bootargs = env::var("MEOWSBI_BOOTARGS").ok();
+
+    let mut chosen = String::new();
+    if let Some(initrd) = initrd {
+        chosen += &format!(r#"
+        global_asm!("
+            .section .initrd, \"a\", %progbits
+            .incbin \"{}\"
+        ");
+        pub const HAS_INITRD: bool = true;
+        "#, initrd);
+    } else {
+        chosen += "pub const HAS_INITRD: bool = false;\n";
+    }
+    chosen += &format!("pub const BOOTARGS: Option<&str> = {:?};\n", bootargs);
+    fs::write(&chosen_rs, chosen).unwrap();
+
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-var-changed=MEOWSBI_PAYLOAD");
+    println!("cargo:rerun-if-var-changed=MEOWSBI_HART_MAX");
+    println!("cargo:rerun-if-var-changed=MEOWSBI_FDT_SLACK");
+    println!("cargo:rerun-if-var-changed=MEOWSBI_INITRD");
+    println!("cargo:rerun-if-var-changed=MEOWSBI_BOOTARGS");
 }
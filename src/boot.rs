@@ -6,27 +6,78 @@ pub unsafe extern "C" fn entry() -> ! {
     // Setup registers
     setup_register();
     setup_stack();
+    llvm_asm!("mv a3, a2"); // fw_dynamic_info from the previous stage
     llvm_asm!("la a2, payload");
 
     llvm_asm!("j boot");
     unreachable!();
 }
 
+// Resets the stack of the current hart, and parks it until the next hart_start / resume
+#[naked]
+pub unsafe extern "C" fn reenter(_hartid: usize) -> ! {
+    setup_stack();
+
+    llvm_asm!("j park");
+    unreachable!();
+}
+
+/**
+ * Sets sp to the top of the storage slot of the current hart
+ *
+ * The slot is looked up in mem::HART_IDS, or allocated from mem::HART_NEXT if this hart
+ * hasn't been here before. Harts that don't fit in HART_MAX are parked forever.
+ * LR/SC is used instead of AMOs, which are not available on all platforms.
+ */
 #[naked]
 unsafe fn setup_stack() {
-    // a0 and a1 is perserved
-    llvm_asm!("mv s0, a0");
-    llvm_asm!("mv s1, a1");
+    // a0, a1 and a2 is perserved
+    llvm_asm!(concat!(r#"
+        mv s0, a0
+        mv s1, a1
+        mv s2, a2
+
+        li t0, 0
+    1:
+        beq t0, $1, 2f
+        slli t3, t0, 3
+        add t3, t3, $0
+        ld t3, 0(t3)
+        beq t3, s0, 3f
+        addi t0, t0, 1
+        j 1b
+
+    2:
+        lr.d t0, ($2)
+        addi t3, t0, 1
+        sc.d t4, t3, ($2)
+        bnez t4, 2b
+
+        bgeu t0, $1, 4f
+        slli t3, t0, 3
+        add t3, t3, $0
+        sd s0, 0(t3)
+        fence rw, rw
+
+    3:
+        addi t0, t0, 1
+        slli t0, t0, "#, crate::HART_STORE_SHIFT_STR!(), r#"
+        add sp, $3, t0
+        j 5f
 
-    llvm_asm!("addi t0, a0, 1");
-    llvm_asm!(concat!("slli t0, t0, ", crate::HART_STORE_SHIFT_STR!()));
-    llvm_asm!("mv sp, $0" :: "r"(
-        &crate::mem::STORAGE as *const _ as usize
-    ) :: "volatile");
-    llvm_asm!("add sp, sp, t0");
+    4:
+        wfi
+        j 4b
 
-    llvm_asm!("mv a0, s0");
-    llvm_asm!("mv a1, s1");
+    5:
+        mv a0, s0
+        mv a1, s1
+        mv a2, s2
+    "#) :: "r"(&crate::mem::HART_IDS as *const _ as usize),
+           "r"(crate::HART_MAX),
+           "r"(&crate::mem::HART_NEXT as *const _ as usize),
+           "r"(&crate::mem::STORAGE as *const _ as usize)
+        : "a0", "a1", "a2", "t0", "t3", "t4", "s0", "s1", "s2", "memory" : "volatile");
 }
 
 macro_rules! clear_reg {
@@ -40,7 +91,7 @@ unsafe fn setup_register() {
     // FENCE.I
     llvm_asm!("fence.i");
 
-    // Clear everything except ra, a0(hartid) and a1(fdt addr)
+    // Clear everything except ra, a0(hartid), a1(fdt addr) and a2(fw_dynamic_info)
     clear_reg!(sp);
     clear_reg!(gp);
     clear_reg!(tp);
@@ -51,7 +102,7 @@ unsafe fn setup_register() {
     clear_reg!(s1);
     // a0 is hartid
     // a1 is fdt addr
-    clear_reg!(a2);
+    // a2 is fw_dynamic_info, if any
     clear_reg!(a3);
     clear_reg!(a4);
     clear_reg!(a5);
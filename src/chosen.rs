@@ -0,0 +1,69 @@
+use crate::fdt_mut::{FDTError, FDTMut};
+
+extern "C" {
+    fn _initrd_start();
+    fn _initrd_end();
+}
+
+include!(concat!(env!("OUT_DIR"), "/chosen_content.rs"));
+
+const PAGE_SIZE: usize = 0x1000;
+
+/**
+ * Moves the embedded initrd right below the FDT copy, returns its (start, end)
+ *
+ * This happens before the payload is loaded, because the initrd is embedded right after
+ * the payload, which may be overwritten by the kernel image.
+ */
+pub fn place_initrd(below: usize) -> Option<(usize, usize)> {
+    if !HAS_INITRD {
+        return None;
+    }
+
+    let size = _initrd_end as usize - _initrd_start as usize;
+    let start = match below.checked_sub(size) {
+        Some(start) => start & !(PAGE_SIZE - 1),
+        None => {
+            crate::mprintln!("Initrd of size 0x{:X} doesn't fit below 0x{:016X}", size, below).unwrap();
+            panic!();
+        }
+    };
+
+    if !crate::mem::s_accessible(start, size, crate::pmp::W) {
+        crate::mprintln!("Initrd at 0x{:016X} overlaps with firmware", start).unwrap();
+        panic!();
+    }
+
+    // The embedded payload is still to be relocated, so it must survive the copy
+    if crate::payload::HAS_PAYLOAD && start < crate::payload::_payload_end as usize {
+        crate::mprintln!("Initrd at 0x{:016X} overlaps with the embedded payload", start).unwrap();
+        panic!();
+    }
+
+    crate::mprintln!("Initrd relocation 0x{:016X} -> 0x{:016X}, size 0x{:X}", _initrd_start as usize, start, size).unwrap();
+    unsafe {
+        core::ptr::copy(_initrd_start as *const u8, start as *mut u8, size);
+    }
+
+    Some((start, start + size))
+}
+
+// Writes the initrd location and bootargs into /chosen
+pub fn fixup(fdt: &mut FDTMut, initrd: Option<(usize, usize)>) -> Result<(), FDTError> {
+    if initrd.is_none() && BOOTARGS.is_none() {
+        return Ok(());
+    }
+
+    let chosen = fdt.subnode_or_add(fdt.root(), "chosen")?;
+
+    if let Some((start, end)) = initrd {
+        fdt.set_property_u64(chosen, "linux,initrd-start", start as u64)?;
+        fdt.set_property_u64(chosen, "linux,initrd-end", end as u64)?;
+    }
+
+    if let Some(bootargs) = BOOTARGS {
+        fdt.set_property_str(chosen, "bootargs", bootargs)?;
+    }
+
+    Ok(())
+}
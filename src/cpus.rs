@@ -0,0 +1,292 @@
+use crate::fdt_mut::{FDTError, FDTMut};
+use core::sync::atomic::Ordering;
+
+/**
+ * CPU node fixups
+ *
+ * Every hart checks in during its boot, recording what it actually supports.
+ * The boot hart then fixes up the FDT accordingly, before entering the payload.
+ */
+
+const CHECK_IN_TIMEOUT: usize = 10_000_000; // In cycles
+
+// States of HartData::checked_in
+const CHECK_IN_NONE: usize = 0;
+const CHECK_IN_BUSY: usize = 1; // Reading the FDT
+const CHECK_IN_DONE: usize = 2;
+const CHECK_IN_LATE: usize = 3; // Given up by the boot hart, which may be editing the FDT
+
+// Supported satp modes, from the widest
+const SATP_MODES: [(riscv::register::satp::Mode, usize, &str); 3] = [
+    (riscv::register::satp::Mode::Sv57, 10, "riscv,sv57"),
+    (riscv::register::satp::Mode::Sv48, 9, "riscv,sv48"),
+    (riscv::register::satp::Mode::Sv39, 8, "riscv,sv39"),
+];
+const MMU_NONE: &str = "riscv,none";
+
+// Canonical order of single-letter extensions in riscv,isa
+const ISA_ORDER: &[u8] = b"imafdqcbjtpvh";
+
+// Local interrupts that are M-mode only: software, timer and external
+const M_IRQS: [u32; 3] = [3, 7, 11];
+
+// satp is WARL, so writing an unsupported mode leaves it unchanged
+fn probe_satp_mode() -> Option<usize> {
+    use riscv::register::satp;
+
+    let mut found = None;
+    for (mode, bits, _) in SATP_MODES.iter() {
+        unsafe { satp::set(*mode, 0, 0) };
+        if satp::read().bits() >> 60 == *bits {
+            found = Some(*bits);
+            break;
+        }
+    }
+
+    satp::write(0);
+    found
+}
+
+/**
+ * Called by warm harts before they read the FDT, returns false if they are too late
+ *
+ * The boot hart edits the FDT in place once every hart checked in, or timed out. Late harts
+ * must not touch it anymore, and are removed from the system.
+ */
+pub fn begin_check_in() -> bool {
+    let hartid = riscv::register::mhartid::read();
+    let data = crate::mem::data(hartid);
+
+    if data.checked_in.compare_and_swap(CHECK_IN_NONE, CHECK_IN_BUSY, Ordering::AcqRel) != CHECK_IN_NONE {
+        return false;
+    }
+
+    // Disabled while we were getting here
+    core::sync::atomic::fence(Ordering::SeqCst);
+    if !crate::mem::hart_enabled(hartid) {
+        data.checked_in.store(CHECK_IN_LATE, Ordering::Release);
+        return false;
+    }
+
+    true
+}
+
+pub fn check_in() {
+    let data = crate::mem::local_data();
+    data.misa = riscv::register::misa::read().map(|m| m.bits()).unwrap_or(0);
+    data.satp_mode = probe_satp_mode();
+    data.checked_in.store(CHECK_IN_DONE, Ordering::Release);
+}
+
+fn checked_in(hartid: usize) -> Option<&'static mut crate::mem::HartData> {
+    crate::mem::try_data(hartid).filter(|d| d.checked_in.load(Ordering::Acquire) == CHECK_IN_DONE)
+}
+
+// Waits for all enabled harts to check in. On timeout, harts that haven't started reading the FDT are disabled.
+pub fn wait_check_in() {
+    let start = riscv::register::mcycle::read();
+
+    while !crate::mem::enabled_harts().all(|h| checked_in(h).is_some()) {
+        if riscv::register::mcycle::read() - start > CHECK_IN_TIMEOUT {
+            crate::mprintln!("Timeout waiting for harts to check in").unwrap();
+            give_up_late_harts();
+            return;
+        }
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+fn give_up_late_harts() {
+    let mut late = [0; crate::HART_MAX];
+    let mut cnt = 0;
+    for hartid in crate::mem::enabled_harts().filter(|h| checked_in(*h).is_none()) {
+        late[cnt] = hartid;
+        cnt += 1;
+    }
+
+    for &hartid in &late[..cnt] {
+        if crate::mem::hart_index(hartid).is_some() {
+            // Harts already reading the FDT are making progress, so they are waited for
+            let data = crate::mem::data(hartid);
+            if data.checked_in.compare_and_swap(CHECK_IN_NONE, CHECK_IN_LATE, Ordering::AcqRel) == CHECK_IN_BUSY {
+                while data.checked_in.load(Ordering::Acquire) == CHECK_IN_BUSY {
+                    core::sync::atomic::spin_loop_hint();
+                }
+                continue;
+            }
+            crate::mem::disable_hart(hartid);
+        } else {
+            // Not in MeowSBI yet. Once disabled, begin_check_in turns it away, unless it got a slot
+            // and passed the check in between.
+            crate::mem::disable_hart(hartid);
+            if crate::mem::hart_index(hartid).is_some() {
+                let data = crate::mem::data(hartid);
+                data.checked_in.compare_and_swap(CHECK_IN_NONE, CHECK_IN_LATE, Ordering::AcqRel);
+                while data.checked_in.load(Ordering::Acquire) == CHECK_IN_BUSY {
+                    core::sync::atomic::spin_loop_hint();
+                }
+            }
+        }
+
+        crate::mprintln!("Hart {} is unavailable", hartid).unwrap();
+    }
+}
+
+fn has_ext(misa: usize, ext: u8) -> bool {
+    misa & (1 << (ext - b'a')) != 0
+}
+
+// Checks that all single-letter extensions in riscv,isa are reported by misa
+fn isa_matches(isa: &[u8], misa: usize) -> bool {
+    let isa = isa.split(|b| *b == 0).next().unwrap_or(&[]);
+    if isa.len() < 4 || &isa[..4] != b"rv64" {
+        return false;
+    }
+
+    for &ext in &isa[4..] {
+        let ok = match ext {
+            // Multi-letter extensions are not described by misa
+            b'_' | b'z' | b'x' => break,
+            b'g' => b"imafd".iter().all(|e| has_ext(misa, *e)),
+            b'a'..=b'y' => has_ext(misa, ext),
+            _ => false,
+        };
+
+        if !ok {
+            return false;
+        }
+    }
+
+    true
+}
+
+// Builds riscv,isa from misa into buf, returns its length
+fn isa_from_misa(buf: &mut [u8; 32], misa: usize) -> usize {
+    buf[..4].copy_from_slice(b"rv64");
+    let mut len = 4;
+    for &ext in ISA_ORDER {
+        if has_ext(misa, ext) {
+            buf[len] = ext;
+            len += 1;
+        }
+    }
+    len
+}
+
+fn mmu_mode(name: &[u8]) -> Option<usize> {
+    let name = name.split(|b| *b == 0).next().unwrap_or(&[]);
+    if name == MMU_NONE.as_bytes() {
+        return Some(0);
+    }
+
+    SATP_MODES.iter().find(|(_, _, n)| n.as_bytes() == name).map(|(_, bits, _)| *bits)
+}
+
+fn fixup_cpu(fdt: &mut FDTMut, cpu: usize) -> Result<(), FDTError> {
+    let hartid = match fdt.property(cpu, "reg").filter(|p| p.len() >= 4) {
+        Some(reg) => u32::from_be_bytes([reg[0], reg[1], reg[2], reg[3]]) as usize,
+        None => return Ok(()),
+    };
+
+    let okay = fdt
+        .property(cpu, "status")
+        .map(|p| p == b"okay\0" || p == b"ok\0")
+        .unwrap_or(true);
+    if !okay {
+        return Ok(());
+    }
+
+    let data = match checked_in(hartid) {
+        Some(data) => data,
+        // We are about to enter the kernel on this hart, so it must stay visible
+        None if hartid == riscv::register::mhartid::read() => return Ok(()),
+        None => {
+            crate::mprintln!("Hart {} never checked in, disabling", hartid).unwrap();
+            return fdt.set_property_str(cpu, "status", "disabled");
+        }
+    };
+
+    if data.misa != 0 {
+        // Multi-letter extensions are kept, since misa can't tell about them
+        let mut tail = [0; 256];
+        let (matches, tail_len) = match fdt.property(cpu, "riscv,isa") {
+            Some(isa) => {
+                let isa = isa.split(|b| *b == 0).next().unwrap_or(&[]);
+                let rest = isa.iter().position(|b| *b == b'_').map(|i| &isa[i..]).unwrap_or(&[]);
+                let len = if rest.len() <= tail.len() { rest.len() } else { 0 };
+                tail[..len].copy_from_slice(&rest[..len]);
+                (isa_matches(isa, data.misa), len)
+            }
+            None => (false, 0),
+        };
+
+        if !matches {
+            let mut isa = [0; 32];
+            let len = isa_from_misa(&mut isa, data.misa);
+            crate::mprintln!(
+                "Hart {} riscv,isa rewritten to {}",
+                hartid,
+                core::str::from_utf8(&isa[..len]).unwrap()
+            )
+            .unwrap();
+            fdt.set_property_with(cpu, "riscv,isa", len + tail_len + 1, |buf| {
+                buf[..len].copy_from_slice(&isa[..len]);
+                buf[len..len + tail_len].copy_from_slice(&tail[..tail_len]);
+            })?;
+        }
+    }
+
+    // Narrower modes are always supported along with wider ones
+    let supported = data.satp_mode.unwrap_or(0);
+    let claimed = fdt.property(cpu, "mmu-type").and_then(mmu_mode);
+    if claimed.map_or(true, |c| c > supported) {
+        let name = SATP_MODES
+            .iter()
+            .find(|(_, bits, _)| *bits == supported)
+            .map(|(_, _, n)| *n)
+            .unwrap_or(MMU_NONE);
+        fdt.set_property_str(cpu, "mmu-type", name)?;
+    }
+
+    Ok(())
+}
+
+// M-mode contexts are marked with an invalid IRQ instead of being deleted, since the index of each entry is significant
+fn fixup_interrupts(fdt: &mut FDTMut) {
+    const COMPATS: [&str; 5] = ["riscv,plic0", "sifive,plic-1.0.0", "clint0", "riscv,clint0", "sifive,clint0"];
+
+    let mut cur = Some(fdt.root());
+    while let Some(node) = cur {
+        if COMPATS.iter().any(|c| fdt.is_compatible(node, c)) {
+            if let Some(prop) = fdt.property_mut(node, "interrupts-extended") {
+                // (phandle, irq) pairs
+                for entry in prop.chunks_exact_mut(8) {
+                    let irq = u32::from_be_bytes([entry[4], entry[5], entry[6], entry[7]]);
+                    if M_IRQS.contains(&irq) {
+                        entry[4..].copy_from_slice(&core::u32::MAX.to_be_bytes());
+                    }
+                }
+            }
+        }
+
+        cur = fdt.next_node(node);
+    }
+}
+
+pub fn fixup(fdt: &mut FDTMut) -> Result<(), FDTError> {
+    // Edits move the following nodes, so cpu nodes are looked up by index each time
+    for i in 0.. {
+        let cpus = fdt.node("/cpus").ok_or(FDTError::NotFound)?;
+        let cpu = match fdt.nth_subnode(cpus, i) {
+            Some(cpu) => cpu,
+            None => break,
+        };
+
+        if fdt.property(cpu, "device_type").map(|p| p == b"cpu\0").unwrap_or(false) {
+            fixup_cpu(fdt, cpu)?;
+        }
+    }
+
+    fixup_interrupts(fdt);
+    Ok(())
+}
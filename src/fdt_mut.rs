@@ -0,0 +1,454 @@
+/**
+ * In-place FDT editor
+ *
+ * The fdt crate is read-only, so fixups are done directly on the relocated blob.
+ * Blocks are expected in the usual order: header, rsvmap, struct, strings.
+ * The blob grows into the slack space after it, up to the capacity given in FDTMut::new.
+ * Nodes are addressed by their offset in the blob. Edits only move data after the edited location,
+ * so the offsets of the edited node and of nodes before it stay valid.
+ */
+
+mod header {
+    pub const MAGIC: usize = 0;
+    pub const TOTALSIZE: usize = 4;
+    pub const OFF_STRUCT: usize = 8;
+    pub const OFF_STRINGS: usize = 12;
+    pub const OFF_RSVMAP: usize = 16;
+    pub const SIZE_STRINGS: usize = 32;
+    pub const SIZE_STRUCT: usize = 36;
+}
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FDTError {
+    BadMagic,
+    BadLayout,
+    BadStructure,
+    NoSpace,
+    NotFound,
+}
+
+pub struct FDTMut {
+    base: *mut u8,
+    capacity: usize,
+}
+
+fn align4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+impl FDTMut {
+    pub unsafe fn new(base: *mut u8, capacity: usize) -> Result<FDTMut, FDTError> {
+        let fdt = FDTMut { base, capacity };
+
+        if fdt.header(header::MAGIC) != FDT_MAGIC {
+            return Err(FDTError::BadMagic);
+        }
+
+        let struct_end = fdt.header(header::OFF_STRUCT) + fdt.header(header::SIZE_STRUCT);
+        let strings_end = fdt.header(header::OFF_STRINGS) + fdt.header(header::SIZE_STRINGS);
+        if fdt.header(header::OFF_RSVMAP) > fdt.header(header::OFF_STRUCT)
+            || struct_end > fdt.header(header::OFF_STRINGS)
+            || strings_end > fdt.header(header::TOTALSIZE)
+            || fdt.total_size() > capacity
+        {
+            return Err(FDTError::BadLayout);
+        }
+
+        Ok(fdt)
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.header(header::TOTALSIZE) as usize
+    }
+
+    fn header(&self, field: usize) -> u32 {
+        self.read_u32(field)
+    }
+
+    fn set_header(&mut self, field: usize, val: u32) {
+        self.write_u32(field, val)
+    }
+
+    fn read_u32(&self, off: usize) -> u32 {
+        u32::from_be_bytes(unsafe { core::ptr::read_unaligned(self.base.add(off) as *const [u8; 4]) })
+    }
+
+    fn write_u32(&mut self, off: usize, val: u32) {
+        unsafe { core::ptr::write_unaligned(self.base.add(off) as *mut [u8; 4], val.to_be_bytes()) }
+    }
+
+    fn bytes(&self, off: usize, len: usize) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.base.add(off), len) }
+    }
+
+    fn bytes_mut(&mut self, off: usize, len: usize) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.base.add(off), len) }
+    }
+
+    // NUL-terminated string at off, without the terminator
+    fn cstr(&self, off: usize) -> &[u8] {
+        let mut len = 0;
+        while unsafe { *self.base.add(off + len) } != 0 {
+            len += 1;
+        }
+        self.bytes(off, len)
+    }
+
+    /**
+     * Inserts len bytes of space at off, moving everything after it
+     * Header offsets of blocks starting at or after off are adjusted accordingly
+     */
+    fn make_space(&mut self, off: usize, len: usize) -> Result<(), FDTError> {
+        let total = self.total_size();
+        if total + len > self.capacity {
+            return Err(FDTError::NoSpace);
+        }
+
+        unsafe { core::ptr::copy(self.base.add(off), self.base.add(off + len), total - off) };
+
+        for &field in &[header::OFF_STRUCT, header::OFF_STRINGS] {
+            let block = self.header(field) as usize;
+            if block >= off {
+                self.set_header(field, (block + len) as u32);
+            }
+        }
+        self.set_header(header::TOTALSIZE, (total + len) as u32);
+        Ok(())
+    }
+
+    // Removes len bytes at off, the reverse of make_space
+    fn remove_space(&mut self, off: usize, len: usize) {
+        let total = self.total_size();
+        unsafe { core::ptr::copy(self.base.add(off + len), self.base.add(off), total - off - len) };
+
+        for &field in &[header::OFF_STRUCT, header::OFF_STRINGS] {
+            let block = self.header(field) as usize;
+            if block > off {
+                self.set_header(field, (block - len) as u32);
+            }
+        }
+        self.set_header(header::TOTALSIZE, (total - len) as u32);
+    }
+
+    // Resizes the struct block item at off from old_len to new_len bytes
+    fn resize_struct(&mut self, off: usize, old_len: usize, new_len: usize) -> Result<(), FDTError> {
+        let size = self.header(header::SIZE_STRUCT) as usize;
+        if new_len > old_len {
+            self.make_space(off + old_len, new_len - old_len)?;
+            self.set_header(header::SIZE_STRUCT, (size + new_len - old_len) as u32);
+        } else if new_len < old_len {
+            self.remove_space(off + new_len, old_len - new_len);
+            self.set_header(header::SIZE_STRUCT, (size - (old_len - new_len)) as u32);
+        }
+        Ok(())
+    }
+
+    // Returns the offset of name in the strings block, appending it if absent
+    fn string_offset(&mut self, name: &str) -> Result<u32, FDTError> {
+        let base = self.header(header::OFF_STRINGS) as usize;
+        let size = self.header(header::SIZE_STRINGS) as usize;
+
+        let mut cur = 0;
+        while cur < size {
+            let s = self.cstr(base + cur);
+            if s == name.as_bytes() {
+                return Ok(cur as u32);
+            }
+            cur += s.len() + 1;
+        }
+
+        self.make_space(base + size, name.len() + 1)?;
+        self.bytes_mut(base + size, name.len()).copy_from_slice(name.as_bytes());
+        self.bytes_mut(base + size + name.len(), 1)[0] = 0;
+        self.set_header(header::SIZE_STRINGS, (size + name.len() + 1) as u32);
+        Ok(size as u32)
+    }
+
+    // Returns (token, offset of the next token)
+    fn next_token(&self, off: usize) -> Result<(u32, usize), FDTError> {
+        let struct_end = (self.header(header::OFF_STRUCT) + self.header(header::SIZE_STRUCT)) as usize;
+        if off + 4 > struct_end {
+            return Err(FDTError::BadStructure);
+        }
+
+        let token = self.read_u32(off);
+        let next = match token {
+            FDT_BEGIN_NODE => off + 4 + align4(self.cstr(off + 4).len() + 1),
+            FDT_PROP => off + 12 + align4(self.read_u32(off + 4) as usize),
+            FDT_END_NODE | FDT_NOP | FDT_END => off + 4,
+            _ => return Err(FDTError::BadStructure),
+        };
+        Ok((token, next))
+    }
+
+    pub fn root(&self) -> usize {
+        self.header(header::OFF_STRUCT) as usize
+    }
+
+    pub fn node_name(&self, node: usize) -> &[u8] {
+        self.cstr(node + 4)
+    }
+
+    // Offset of the first token after the node name, where properties start
+    fn node_body(&self, node: usize) -> Result<usize, FDTError> {
+        self.next_token(node).map(|(_, next)| next)
+    }
+
+    // Offset of the END_NODE token of a node
+    fn node_end(&self, node: usize) -> Result<usize, FDTError> {
+        let mut depth = 0;
+        let mut cur = node;
+        loop {
+            let (token, next) = self.next_token(cur)?;
+            match token {
+                FDT_BEGIN_NODE => depth += 1,
+                FDT_END_NODE => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(cur);
+                    }
+                }
+                FDT_END => return Err(FDTError::BadStructure),
+                _ => {}
+            }
+            cur = next;
+        }
+    }
+
+    /**
+     * Direct subnodes of a node, by offset
+     * Takes a callback instead of returning an iterator, since the blob cannot be borrowed across edits
+     */
+    pub fn for_each_subnode<F: FnMut(&Self, usize)>(&self, node: usize, mut f: F) -> Result<(), FDTError> {
+        let end = self.node_end(node)?;
+        let mut cur = self.node_body(node)?;
+        while cur < end {
+            let (token, next) = self.next_token(cur)?;
+            if token == FDT_BEGIN_NODE {
+                f(self, cur);
+                cur = self.node_end(cur)?;
+                cur = self.next_token(cur)?.1;
+            } else {
+                cur = next;
+            }
+        }
+        Ok(())
+    }
+
+    // The n-th direct subnode of a node
+    pub fn nth_subnode(&self, node: usize, n: usize) -> Option<usize> {
+        let mut idx = 0;
+        let mut found = None;
+        self.for_each_subnode(node, |_, sub| {
+            if idx == n {
+                found = Some(sub);
+            }
+            idx += 1;
+        })
+        .ok()?;
+        found
+    }
+
+    // The node following the given one in depth-first order
+    pub fn next_node(&self, node: usize) -> Option<usize> {
+        let mut cur = self.node_body(node).ok()?;
+        loop {
+            let (token, next) = self.next_token(cur).ok()?;
+            match token {
+                FDT_BEGIN_NODE => return Some(cur),
+                FDT_END => return None,
+                _ => cur = next,
+            }
+        }
+    }
+
+    pub fn is_compatible(&self, node: usize, compat: &str) -> bool {
+        self.property(node, "compatible")
+            .map(|p| p.split(|b| *b == 0).any(|s| s == compat.as_bytes()))
+            .unwrap_or(false)
+    }
+
+    // Names without a unit address match any unit address
+    fn name_matches(node_name: &[u8], name: &str) -> bool {
+        if node_name == name.as_bytes() {
+            return true;
+        }
+
+        !name.contains('@')
+            && node_name.len() > name.len()
+            && node_name.starts_with(name.as_bytes())
+            && node_name[name.len()] == b'@'
+    }
+
+    pub fn subnode(&self, node: usize, name: &str) -> Option<usize> {
+        let mut found = None;
+        self.for_each_subnode(node, |fdt, sub| {
+            if found.is_none() && Self::name_matches(fdt.node_name(sub), name) {
+                found = Some(sub);
+            }
+        })
+        .ok()?;
+        found
+    }
+
+    // Looks up a node by its absolute path, e.g. "/cpus/cpu@0"
+    pub fn node(&self, path: &str) -> Option<usize> {
+        path.split('/')
+            .filter(|c| !c.is_empty())
+            .try_fold(self.root(), |node, name| self.subnode(node, name))
+    }
+
+    // Returns the offset of the PROP token
+    fn find_property(&self, node: usize, name: &str) -> Option<usize> {
+        let mut cur = self.node_body(node).ok()?;
+        loop {
+            let (token, next) = self.next_token(cur).ok()?;
+            match token {
+                FDT_PROP => {
+                    let nameoff = self.read_u32(cur + 8) as usize;
+                    let strings = self.header(header::OFF_STRINGS) as usize;
+                    if self.cstr(strings + nameoff) == name.as_bytes() {
+                        return Some(cur);
+                    }
+                }
+                FDT_NOP => {}
+                _ => return None,
+            }
+            cur = next;
+        }
+    }
+
+    pub fn property(&self, node: usize, name: &str) -> Option<&[u8]> {
+        self.find_property(node, name).map(|prop| {
+            let len = self.read_u32(prop + 4) as usize;
+            self.bytes(prop + 12, len)
+        })
+    }
+
+    // For in-place edits that keep the length of the value
+    pub fn property_mut(&mut self, node: usize, name: &str) -> Option<&mut [u8]> {
+        let prop = self.find_property(node, name)?;
+        let len = self.read_u32(prop + 4) as usize;
+        Some(self.bytes_mut(prop + 12, len))
+    }
+
+    // Creates or replaces a property of len bytes, whose value is filled in by fill
+    pub fn set_property_with<F: FnOnce(&mut [u8])>(
+        &mut self,
+        node: usize,
+        name: &str,
+        len: usize,
+        fill: F,
+    ) -> Result<(), FDTError> {
+        // Strings are after the struct block, so this doesn't move the node
+        let nameoff = self.string_offset(name)?;
+
+        let (prop, old_len) = match self.find_property(node, name) {
+            Some(prop) => (prop, 12 + align4(self.read_u32(prop + 4) as usize)),
+            None => {
+                // New properties go before the first subnode
+                let mut cur = self.node_body(node)?;
+                loop {
+                    let (token, next) = self.next_token(cur)?;
+                    if token != FDT_PROP && token != FDT_NOP {
+                        break;
+                    }
+                    cur = next;
+                }
+                (cur, 0)
+            }
+        };
+
+        let new_len = 12 + align4(len);
+        self.resize_struct(prop, old_len, new_len)?;
+
+        self.write_u32(prop, FDT_PROP);
+        self.write_u32(prop + 4, len as u32);
+        self.write_u32(prop + 8, nameoff);
+        let value = self.bytes_mut(prop + 12, new_len - 12);
+        for b in value.iter_mut() {
+            *b = 0;
+        }
+        fill(&mut value[..len]);
+        Ok(())
+    }
+
+    pub fn set_property(&mut self, node: usize, name: &str, value: &[u8]) -> Result<(), FDTError> {
+        self.set_property_with(node, name, value.len(), |buf| buf.copy_from_slice(value))
+    }
+
+    pub fn set_property_u32(&mut self, node: usize, name: &str, value: u32) -> Result<(), FDTError> {
+        self.set_property(node, name, &value.to_be_bytes())
+    }
+
+    pub fn set_property_u64(&mut self, node: usize, name: &str, value: u64) -> Result<(), FDTError> {
+        self.set_property(node, name, &value.to_be_bytes())
+    }
+
+    // Value is stored with its NUL terminator
+    pub fn set_property_str(&mut self, node: usize, name: &str, value: &str) -> Result<(), FDTError> {
+        self.set_property_with(node, name, value.len() + 1, |buf| {
+            buf[..value.len()].copy_from_slice(value.as_bytes())
+        })
+    }
+
+    // Appends an empty subnode, returns its offset
+    pub fn add_subnode(&mut self, node: usize, name: &str) -> Result<usize, FDTError> {
+        let end = self.node_end(node)?;
+        let name_len = align4(name.len() + 1);
+        let len = 4 + name_len + 4;
+
+        self.resize_struct(end, 0, len)?;
+
+        self.write_u32(end, FDT_BEGIN_NODE);
+        for b in self.bytes_mut(end + 4, name_len) {
+            *b = 0;
+        }
+        self.bytes_mut(end + 4, name.len()).copy_from_slice(name.as_bytes());
+        self.write_u32(end + 4 + name_len, FDT_END_NODE);
+        Ok(end)
+    }
+
+    // Returns the subnode, creating it if absent
+    pub fn subnode_or_add(&mut self, node: usize, name: &str) -> Result<usize, FDTError> {
+        match self.subnode(node, name) {
+            Some(sub) => Ok(sub),
+            None => self.add_subnode(node, name),
+        }
+    }
+
+    /**
+     * Appends an entry to the memory reservation block
+     * The block is grown in place, moving the struct and strings blocks after it
+     */
+    pub fn add_memreserve(&mut self, addr: u64, size: u64) -> Result<(), FDTError> {
+        let end = self.header(header::OFF_STRUCT) as usize;
+        let mut cur = self.header(header::OFF_RSVMAP) as usize;
+
+        loop {
+            if cur + 16 > end {
+                return Err(FDTError::BadLayout);
+            }
+
+            let entry = self.bytes(cur, 16);
+            if entry.iter().all(|b| *b == 0) {
+                break;
+            }
+            cur += 16;
+        }
+
+        // cur is the terminator, which is moved after the new entry
+        self.make_space(cur, 16)?;
+        self.bytes_mut(cur, 8).copy_from_slice(&addr.to_be_bytes());
+        self.bytes_mut(cur + 8, 8).copy_from_slice(&size.to_be_bytes());
+        Ok(())
+    }
+}
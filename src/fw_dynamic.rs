@@ -0,0 +1,139 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use riscv::register::mstatus::MPP;
+
+/**
+ * FW_DYNAMIC boot mode
+ *
+ * The previous boot stage (e.g. U-Boot SPL) passes a pointer to fw_dynamic_info in a2,
+ * with the same layout as OpenSBI. If it is absent or invalid, we fall back to FW_JUMP.
+ */
+
+const MAGIC: usize = 0x4942534f; // "OSBI"
+const VERSION_MAX: usize = 2;
+const BOOT_HART_ANY: usize = core::usize::MAX;
+
+// Time the default boot hart waits for the one in fw_dynamic_info to show up, in cycles
+const BOOT_HART_TIMEOUT: usize = 10_000_000;
+
+/**
+ * Hartid doing the cold boot, decided once by the first hart claiming it
+ * Placed in .sdata along with mem::HART_IDS, as all harts use it before .bss is ready.
+ */
+#[link_section = ".sdata"]
+static COLD_HART: AtomicUsize = AtomicUsize::new(BOOT_HART_ANY);
+
+// Only describes the layout, as it's read word by word through read_word
+#[repr(C)]
+#[allow(dead_code)]
+pub struct DynamicInfo {
+    magic: usize,
+    version: usize,
+    next_addr: usize,
+    next_mode: usize,
+    options: usize,
+    boot_hart: usize, // Since version 2
+}
+
+pub struct NextStage {
+    pub addr: usize,
+    pub mode: MPP,
+    pub boot_hart: Option<usize>,
+}
+
+// Loads a word of fw_dynamic_info, which may point anywhere, so faults are caught
+fn read_word(addr: usize) -> Option<usize> {
+    crate::trap::mprv::guarded(|| unsafe {
+        let val: usize;
+        // Uncompressed, as catch_fault skips 4 bytes
+        llvm_asm!(r#"
+        .option push
+        .option norvc
+        ld $0, 0($1)
+        .option pop
+        "# : "=&r"(val) : "r"(addr) :: "volatile");
+        val
+    })
+    .ok()
+}
+
+// Should be called after trap::early_setup
+pub fn parse(info: *const DynamicInfo) -> Option<NextStage> {
+    if info.is_null() || info as usize % core::mem::align_of::<DynamicInfo>() != 0 {
+        return None;
+    }
+
+    let field = |offset: usize| read_word(info as usize + offset * core::mem::size_of::<usize>());
+
+    // Fields are indexed in the order of DynamicInfo.
+    // The rest is only read once magic and version are known to be valid.
+    if field(0)? != MAGIC {
+        return None;
+    }
+    let version = field(1)?;
+    if version > VERSION_MAX {
+        return None;
+    }
+
+    let next_addr = field(2)?;
+    let next_mode = field(3)?;
+
+    // Entering the next stage in M-mode would bypass the firmware entirely
+    let mode = match next_mode {
+        0 => MPP::User,
+        1 => MPP::Supervisor,
+        _ => return None,
+    };
+
+    // QEMU passes next_addr = 0 when there is no -kernel
+    if next_addr == 0 || crate::mem::in_firmware(next_addr) {
+        return None;
+    }
+
+    let boot_hart = if version >= 2 {
+        Some(field(5)?).filter(|h| *h != BOOT_HART_ANY)
+    } else {
+        None
+    };
+
+    Some(NextStage {
+        addr: next_addr,
+        mode,
+        boot_hart,
+    })
+}
+
+fn claim_cold(hartid: usize) -> bool {
+    let prev = COLD_HART.compare_and_swap(BOOT_HART_ANY, hartid, Ordering::AcqRel);
+    prev == BOOT_HART_ANY || prev == hartid
+}
+
+/**
+ * Decides whether this hart does the cold boot
+ *
+ * Without a boot hart from fw_dynamic_info, hart 0 does. Otherwise hart 0 waits for the given
+ * one, and takes over if it never shows up, e.g. if it doesn't exist.
+ */
+pub fn is_cold(hartid: usize, boot_hart: Option<usize>) -> bool {
+    let boot_hart = match boot_hart {
+        Some(boot_hart) if boot_hart != 0 => boot_hart,
+        _ => return hartid == 0 && claim_cold(hartid),
+    };
+
+    if hartid == boot_hart {
+        return claim_cold(hartid);
+    }
+
+    if hartid != 0 {
+        return false;
+    }
+
+    let start = riscv::register::mcycle::read();
+    while riscv::register::mcycle::read() - start < BOOT_HART_TIMEOUT {
+        if COLD_HART.load(Ordering::Acquire) != BOOT_HART_ANY {
+            return false;
+        }
+        core::sync::atomic::spin_loop_hint();
+    }
+
+    claim_cold(hartid)
+}
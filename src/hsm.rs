@@ -0,0 +1,133 @@
+use crate::sbi::{SBIErr, SBIRet};
+
+#[repr(usize)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[allow(dead_code)]
+pub enum HartState {
+    Started = 0,
+    Stopped = 1,
+    StartPending = 2,
+    StopPending = 3,
+    Suspended = 4,
+    SuspendPending = 5,
+    ResumePending = 6,
+}
+
+impl HartState {
+    // Stopped harts are parked in M-mode, so only running or suspended harts may receive IPIs
+    pub fn accepts_ipi(self) -> bool {
+        self == HartState::Started || self == HartState::Suspended
+    }
+}
+
+const SUSPEND_RETENTIVE: usize = 0x0000_0000;
+const SUSPEND_NON_RETENTIVE: usize = 0x8000_0000;
+
+fn interrupt_pending() -> bool {
+    riscv::register::mip::read().bits() & riscv::register::mie::read().bits() != 0
+}
+
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> SBIRet {
+    let data = match crate::mem::try_data(hartid) {
+        Some(data) => data,
+        None => return SBIErr::InvalidParam.into(),
+    };
+
+    if crate::mem::in_firmware(start_addr) {
+        return SBIErr::InvalidAddress.into();
+    }
+
+    if !data.hsm_transit(HartState::Stopped, HartState::StartPending) {
+        return SBIErr::AlreadyAvailable.into();
+    }
+
+    data.hsm_set_next(start_addr, opaque);
+
+    use crate::platform::PlatformOps;
+    crate::mem::local_data().platform().send_ipi(hartid);
+
+    0usize.into()
+}
+
+pub fn hart_stop() -> SBIRet {
+    let hartid = riscv::register::mhartid::read();
+    let data = crate::mem::data(hartid);
+
+    if !data.hsm_transit(HartState::Started, HartState::StopPending) {
+        return SBIErr::Failed.into();
+    }
+
+    data.hsm_set(HartState::Stopped);
+    // park never handles IPIs, so requests posted until now are acked here.
+    // Senders posting later notice that we are stopped and stop waiting.
+    data.ipi_handle();
+    unsafe { crate::boot::reenter(hartid) }
+}
+
+pub fn hart_get_status(hartid: usize) -> SBIRet {
+    match crate::mem::try_data(hartid) {
+        Some(data) => (data.hsm_state() as usize).into(),
+        None => SBIErr::InvalidParam.into(),
+    }
+}
+
+pub fn hart_suspend(suspend_type: usize, resume_addr: usize, opaque: usize) -> SBIRet {
+    let hartid = riscv::register::mhartid::read();
+    let data = crate::mem::data(hartid);
+
+    match suspend_type {
+        SUSPEND_RETENTIVE => {
+            data.hsm_set(HartState::Suspended);
+            while !interrupt_pending() {
+                unsafe { riscv::asm::wfi() };
+            }
+            data.hsm_set(HartState::Started);
+
+            0usize.into()
+        }
+        SUSPEND_NON_RETENTIVE => {
+            if crate::mem::in_firmware(resume_addr) {
+                return SBIErr::InvalidAddress.into();
+            }
+
+            data.hsm_set_next(resume_addr, opaque);
+            data.hsm_set(HartState::Suspended);
+            unsafe { crate::boot::reenter(hartid) }
+        }
+        _ => SBIErr::InvalidParam.into(),
+    }
+}
+
+/**
+ * Parks the current hart in a WFI loop, until it gets started or resumed
+ * Entered with a fresh stack, either from warm boot or from boot::reenter
+ */
+#[no_mangle]
+pub extern "C" fn park(hartid: usize) -> ! {
+    use crate::platform::PlatformOps;
+    let data = crate::mem::data(hartid);
+
+    let (next_addr, opaque) = loop {
+        match data.hsm_state() {
+            HartState::StartPending => {
+                if let Some(next) = data.hsm_take() {
+                    // The IPI was only used to wake us up
+                    data.platform().clear_ipi();
+                    break next;
+                }
+            }
+            HartState::Suspended => {
+                if interrupt_pending() {
+                    data.hsm_set(HartState::ResumePending);
+                    break data.hsm_take().unwrap();
+                }
+            }
+            _ => {}
+        }
+
+        unsafe { riscv::asm::wfi() };
+    };
+
+    data.hsm_set(HartState::Started);
+    crate::next_boot(hartid, next_addr, riscv::register::mstatus::MPP::Supervisor, opaque);
+}
@@ -1,69 +1,329 @@
 use core::sync::atomic::*;
 
+const PAGE_SIZE: usize = 0x1000;
+
+// Ranges spanning more pages than this are flushed entirely
+const FLUSH_THRESHOLD: usize = 64;
+
+const HGATP_VMID_SHIFT: usize = 44;
+const HGATP_VMID_MASK: usize = 0x3FFF << HGATP_VMID_SHIFT;
+
+/**
+ * Range of a remote fence
+ *
+ * asid = None fences all address spaces. Following the SBI spec, start = size = 0 or size = -1
+ * means a full flush.
+ *
+ * vmid is the VMID to fence for HFENCE.GVMA, and the VMID of the caller for HFENCE.VVMA.
+ * None fences all VMs.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Fence {
+    pub start: usize,
+    pub size: usize,
+    pub asid: Option<usize>,
+    pub vmid: Option<usize>,
+}
+
+// Old LLVM doesn't know about the H extension, so hfence.* are hand-encoded with rs1 = a0, rs2 = a1
+macro_rules! hfence {
+    ($insn:literal, $addr:expr, $id:expr) => {
+        unsafe { llvm_asm!(concat!(".word ", $insn) :: "{a0}"($addr), "{a1}"($id) :: "volatile") }
+    };
+}
+
+impl Fence {
+    pub fn new(start: usize, size: usize, asid: Option<usize>, vmid: Option<usize>) -> Self {
+        let size = if start == 0 && size == 0 { core::usize::MAX } else { size };
+        Fence { start, size, asid, vmid }
+    }
+
+    fn is_full(&self) -> bool {
+        self.size == core::usize::MAX || self.size / PAGE_SIZE > FLUSH_THRESHOLD
+    }
+
+    fn pages(&self) -> impl Iterator<Item = usize> {
+        let start = self.start & !(PAGE_SIZE - 1);
+        let end = self.start.saturating_add(self.size);
+        (start..end).step_by(PAGE_SIZE)
+    }
+
+    // Smallest fence covering both
+    pub fn merge(self, other: Fence) -> Fence {
+        let asid = if self.asid == other.asid { self.asid } else { None };
+        let vmid = if self.vmid == other.vmid { self.vmid } else { None };
+        if self.is_full() || other.is_full() {
+            return Fence { start: 0, size: core::usize::MAX, asid, vmid };
+        }
+
+        let start = core::cmp::min(self.start, other.start);
+        let end = core::cmp::max(self.start.saturating_add(self.size), other.start.saturating_add(other.size));
+        Fence { start, size: end - start, asid, vmid }
+    }
+
+    fn sfence_vma(&self) {
+        if self.is_full() {
+            match self.asid {
+                Some(asid) => unsafe { llvm_asm!("sfence.vma zero, $0" :: "r"(asid) :: "volatile") },
+                None => unsafe { riscv::asm::sfence_vma_all() },
+            }
+            return;
+        }
+
+        for addr in self.pages() {
+            match self.asid {
+                Some(asid) => unsafe { llvm_asm!("sfence.vma $0, $1" :: "r"(addr), "r"(asid) :: "volatile") },
+                None => unsafe { llvm_asm!("sfence.vma $0, zero" :: "r"(addr) :: "volatile") },
+            }
+        }
+    }
+
+    // Guest physical addresses are passed shifted right by 2
+    fn hfence_gvma(&self) {
+        let vmid = self.vmid.unwrap_or(0);
+        if self.is_full() {
+            match self.vmid {
+                Some(_) => hfence!("0x62B00073", 0usize, vmid), // hfence.gvma zero, a1
+                None => hfence!("0x62000073", 0usize, 0usize),  // hfence.gvma zero, zero
+            }
+            return;
+        }
+
+        for addr in self.pages() {
+            match self.vmid {
+                Some(_) => hfence!("0x62B50073", addr >> 2, vmid), // hfence.gvma a0, a1
+                None => hfence!("0x62050073", addr >> 2, 0usize),  // hfence.gvma a0, zero
+            }
+        }
+    }
+
+    // HFENCE.VVMA applies to the VMID in hgatp, so the caller's one is installed meanwhile
+    fn hfence_vvma(&self) {
+        let vmid = match self.vmid {
+            Some(vmid) => vmid,
+            None => {
+                // Merged from different VMs, fence all of them
+                hfence!("0x62000073", 0usize, 0usize);
+                return;
+            }
+        };
+
+        let hgatp = hgatp_read();
+        hgatp_write((hgatp & !HGATP_VMID_MASK) | (vmid << HGATP_VMID_SHIFT));
+
+        let asid = self.asid.unwrap_or(0);
+        if self.is_full() {
+            match self.asid {
+                Some(_) => hfence!("0x22B00073", 0usize, asid), // hfence.vvma zero, a1
+                None => hfence!("0x22000073", 0usize, 0usize),  // hfence.vvma zero, zero
+            }
+        } else {
+            for addr in self.pages() {
+                match self.asid {
+                    Some(_) => hfence!("0x22B50073", addr, asid), // hfence.vvma a0, a1
+                    None => hfence!("0x22050073", addr, 0usize),  // hfence.vvma a0, zero
+                }
+            }
+        }
+
+        hgatp_write(hgatp);
+    }
+}
+
+fn hgatp_read() -> usize {
+    let hgatp: usize;
+    unsafe { llvm_asm!("csrr $0, 0x680" : "=r"(hgatp) ::: "volatile") };
+    hgatp
+}
+
+fn hgatp_write(hgatp: usize) {
+    unsafe { llvm_asm!("csrw 0x680, $0" :: "r"(hgatp) :: "volatile") };
+}
+
+// VMID of the current VM, for HFENCE.VVMA requests
+pub fn current_vmid() -> usize {
+    (hgatp_read() & HGATP_VMID_MASK) >> HGATP_VMID_SHIFT
+}
+
+pub fn has_h() -> bool {
+    crate::mem::local_data().misa & (1 << (b'h' - b'a')) != 0
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum IPIReq {
     S_IPI, // S-mode IPI
     FENCE_I,
-    SFENCE_VMA,
+    SFENCE_VMA(Fence),
+    HFENCE_GVMA(Fence),
+    HFENCE_VVMA(Fence),
 }
 
-static LOCK: AtomicBool = AtomicBool::new(false);
+// Number of fence slots per hart, one per fence request type
+pub const FENCE_KINDS: usize = 3;
 
-pub fn send_ipi(mask: usize, req: IPIReq) {
-    // crate::mprintln!("[MeowSBI] IPI send: {:?}", req).unwrap();
+impl IPIReq {
+    // Bit in HartData::ipi_pending
+    pub fn bit(self) -> usize {
+        match self {
+            IPIReq::S_IPI => 1 << 0,
+            IPIReq::FENCE_I => 1 << 1,
+            IPIReq::SFENCE_VMA(_) => 1 << 2,
+            IPIReq::HFENCE_GVMA(_) => 1 << 3,
+            IPIReq::HFENCE_VVMA(_) => 1 << 4,
+        }
+    }
 
-    let started_mask = (1 << crate::HART_CNT) - 1;
-    let mask = mask & started_mask;
+    // Slot of ranged fences in HartData::ipi_fence
+    pub fn fence(self) -> Option<(usize, Fence)> {
+        match self {
+            IPIReq::SFENCE_VMA(fence) => Some((0, fence)),
+            IPIReq::HFENCE_GVMA(fence) => Some((1, fence)),
+            IPIReq::HFENCE_VVMA(fence) => Some((2, fence)),
+            _ => None,
+        }
+    }
+
+    fn from_fence(slot: usize, fence: Fence) -> Self {
+        match slot {
+            0 => IPIReq::SFENCE_VMA(fence),
+            1 => IPIReq::HFENCE_GVMA(fence),
+            _ => IPIReq::HFENCE_VVMA(fence),
+        }
+    }
+
+    // Fences are only complete once the target has executed them
+    fn needs_ack(self) -> bool {
+        self != IPIReq::S_IPI
+    }
+}
+
+/**
+ * Each hart has a bitmap of pending request types, so identical requests from different senders coalesce.
+ * Ranged fences are merged into per-hart slots before the bit is set.
+ *
+ * The target bumps ipi_seq to an odd value before taking the bitmap, and to an even value after
+ * handling it. A sender which posted its request when ipi_seq was s knows it has been handled once
+ * ipi_seq reaches ipi_done(s). Since every post is followed by an IPI, a handling pass always follows.
+ *
+ * We don't have AMOs, so everything is built on compare_and_swap, which is lowered to LR/SC.
+ */
+pub fn fetch_or(atomic: &AtomicUsize, bits: usize) -> usize {
+    let mut cur = atomic.load(Ordering::Relaxed);
+    loop {
+        let prev = atomic.compare_and_swap(cur, cur | bits, Ordering::SeqCst);
+        if prev == cur {
+            return prev;
+        }
+        cur = prev;
+    }
+}
 
-    // Acquire lock
-    while LOCK.compare_and_swap(false, true, Ordering::Acquire) {
-        spin_loop_hint();
-        if riscv::register::mip::read().msoft() {
-            // Other core triggered an IPI, handle right now
-            crate::mem::local_data().ipi_handle();
+pub fn take(atomic: &AtomicUsize) -> usize {
+    let mut cur = atomic.load(Ordering::Relaxed);
+    loop {
+        let prev = atomic.compare_and_swap(cur, 0, Ordering::SeqCst);
+        if prev == cur {
+            return prev;
         }
+        cur = prev;
+    }
+}
+
+pub fn ipi_done(seq: usize) -> usize {
+    if seq & 1 == 0 {
+        // Idle: the next pass takes our request
+        seq + 2
+    } else {
+        // A pass is in progress, and may have taken the bitmap before our request was posted
+        seq + 3
     }
+}
+
+pub fn send_ipi(harts: impl Iterator<Item = usize>, req: IPIReq) {
+    // crate::mprintln!("[MeowSBI] IPI send: {:?}", req).unwrap();
 
     let cur_hart = riscv::register::mhartid::read();
-    let mut sending = mask;
-    let mut waiting = sending;
+    // (hartid, sequence number it needs to reach) of each target we wait for
+    let mut waiting = [(0usize, 0usize); crate::HART_MAX];
+    let mut waiting_cnt = 0;
 
     let platform = crate::mem::local_data().platform();
     use crate::platform::PlatformOps;
 
-    while sending != 0 {
-        let current = sending.trailing_zeros() as usize;
+    for current in harts {
+        crate::pmu::fw_count(match req {
+            IPIReq::S_IPI => crate::pmu::FWEvent::IPISent,
+            IPIReq::FENCE_I => crate::pmu::FWEvent::FenceISent,
+            IPIReq::SFENCE_VMA(_) => crate::pmu::FWEvent::SFenceVMASent,
+            IPIReq::HFENCE_GVMA(_) => crate::pmu::FWEvent::HFenceGVMASent,
+            IPIReq::HFENCE_VVMA(_) => crate::pmu::FWEvent::HFenceVVMASent,
+        });
 
         if current == cur_hart {
             handle_ipi(req);
-        } else {
-            crate::mem::data(current).ipi_set(req);
-            platform.send_ipi(current);
-        }
+        } else if let Some(target) = crate::mem::try_data(current) {
+            // Harts which never entered MeowSBI are skipped
+            if target.hsm_state().accepts_ipi() {
+                let done = target.ipi_post(req);
+                platform.send_ipi(current);
 
-        sending = sending ^ (1 << current);
+                // Each hart appears at most once, so this never exceeds HART_MAX
+                if req.needs_ack() {
+                    waiting[waiting_cnt] = (current, done);
+                    waiting_cnt += 1;
+                }
+            }
+        }
     }
 
-    while waiting != 0 {
-        let current = waiting.trailing_zeros() as usize;
+    for &(current, done) in &waiting[..waiting_cnt] {
+        let target = crate::mem::data(current);
 
-        if current == cur_hart {
-            // Does nothing
-        } else {
-            // crate::mem::data(current).ipi_wait();
+        while target.ipi_seq.load(Ordering::Acquire) < done {
+            // The target may have stopped after the request was posted, and it won't be handled
+            if !target.hsm_state().accepts_ipi() {
+                break;
+            }
+
+            spin_loop_hint();
+            if riscv::register::mip::read().msoft() {
+                // Other core may be waiting for us, handle right now
+                crate::mem::local_data().ipi_handle();
+            }
         }
+    }
+}
 
-        waiting = waiting ^ (1 << current);
+// Handles all requests set in a bitmap taken from ipi_pending, with the fences taken from the slots
+pub fn handle_pending(pending: usize, fences: [Option<Fence>; FENCE_KINDS]) {
+    for req in [IPIReq::S_IPI, IPIReq::FENCE_I].iter() {
+        if pending & req.bit() != 0 {
+            handle_ipi(*req);
+        }
     }
 
-    LOCK.store(false, Ordering::Release);
+    // A slot may have been filled by a sender which hasn't set its bit yet, that's handled early
+    for (slot, fence) in fences.iter().enumerate() {
+        if let Some(fence) = fence {
+            handle_ipi(IPIReq::from_fence(slot, *fence));
+        }
+    }
 }
 
 pub fn handle_ipi(req: IPIReq) {
     // crate::mprintln!("[MeowSBI] IPI recv: {:?}", req).unwrap();
+    crate::pmu::fw_count(match req {
+        IPIReq::S_IPI => crate::pmu::FWEvent::IPIRecvd,
+        IPIReq::FENCE_I => crate::pmu::FWEvent::FenceIRecvd,
+        IPIReq::SFENCE_VMA(_) => crate::pmu::FWEvent::SFenceVMARecvd,
+        IPIReq::HFENCE_GVMA(_) => crate::pmu::FWEvent::HFenceGVMARecvd,
+        IPIReq::HFENCE_VVMA(_) => crate::pmu::FWEvent::HFenceVVMARecvd,
+    });
     match req {
         IPIReq::S_IPI => unsafe { riscv::register::mip::set_ssoft() },
         IPIReq::FENCE_I => unsafe { llvm_asm!("FENCE.I") },
-        IPIReq::SFENCE_VMA => unsafe { riscv::asm::sfence_vma_all() },
+        IPIReq::SFENCE_VMA(fence) => fence.sfence_vma(),
+        IPIReq::HFENCE_GVMA(fence) => fence.hfence_gvma(),
+        IPIReq::HFENCE_VVMA(fence) => fence.hfence_vvma(),
     }
 }
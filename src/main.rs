@@ -17,6 +17,11 @@
 use riscv;
 
 mod boot;
+mod chosen;
+mod cpus;
+mod fdt_mut;
+mod fw_dynamic;
+mod hsm;
 mod ipi;
 mod lang_items;
 mod mem;
@@ -26,26 +31,34 @@ mod serial;
 mod trap;
 mod utils;
 mod payload;
+mod pmp;
+mod pmu;
+mod reserved;
 
 use platform::PlatformOps;
 
-const HART_CNT: usize = 2;
+// HART_MAX and FDT_SLACK, configured through MEOWSBI_HART_MAX and MEOWSBI_FDT_SLACK
+include!(concat!(env!("OUT_DIR"), "/config.rs"));
+
+// Must match HART_STORE_SHIFT_STR, which boot::setup_stack uses to find the stack top
 const HART_STORE_SIZE: usize = 1 << 18;
 #[macro_export]
 macro_rules! HART_STORE_SHIFT_STR {
     () => {
-        "16"
+        "18"
     };
 }
 
-type PLATFORM = platform::meowv64::MeowV64;
+type PLATFORM = platform::Platform;
 
 use core::sync::atomic::*;
 static mut FDT_RELOCATED_ADDR: *mut u8 = 0 as *mut u8;
+static mut FDT_CAPACITY: usize = 0;
 static WARM_BOOT_FIRE: AtomicBool = AtomicBool::new(false);
 
 extern "C" {
     fn _fw_start();
+    fn _fw_rw_start();
     fn _fw_end();
 }
 
@@ -55,8 +68,18 @@ extern "C" {
  */
 
 #[no_mangle]
-extern "C" fn boot(hartid: usize, fdt_addr: *const u8, payload_addr: *const u8) -> ! {
-    if hartid != 0 {
+extern "C" fn boot(
+    hartid: usize,
+    fdt_addr: *const u8,
+    payload_addr: *const u8,
+    dynamic_info: *const fw_dynamic::DynamicInfo,
+) -> ! {
+    // fw_dynamic_info may point anywhere, so faults must be caught while parsing it
+    trap::early_setup();
+    let dynamic = fw_dynamic::parse(dynamic_info);
+
+    // The boot hart is either given by fw_dynamic_info, or hart 0
+    if !fw_dynamic::is_cold(hartid, dynamic.as_ref().and_then(|d| d.boot_hart)) {
         warm_boot(hartid)
     }
 
@@ -82,27 +105,68 @@ extern "C" fn boot(hartid: usize, fdt_addr: *const u8, payload_addr: *const u8)
             PLATFORM::new(hartid, fdt),
         );
     }
+    // Resets mtime and takes over the UART from early print
+    mem::data(hartid).platform().early_init(true);
 
     mprint!(include_str!("./motd.txt")).unwrap();
 
+    crate::mprintln!("Platform: {}", mem::data(hartid).platform().name()).unwrap();
     crate::mprintln!("FDT relocated to 0x{:016X}", fdt_addr as usize).unwrap();
 
-    // Setup pmp
-    setup_pmp();
+    mem::probe_harts(&unsafe { fdt::FDT::from_raw(fdt_addr) }.unwrap());
+
+    pmu::init(&unsafe { fdt::FDT::from_raw(fdt_addr) }.unwrap());
+    pmu::hart_init();
 
     // Setup mtvec
     trap::setup();
 
+    // Register pmp regions, programmed right before entering the payload
+    pmp::detect(&unsafe { fdt::FDT::from_raw(fdt_addr) }.unwrap(), hartid);
+    let (fw_start, fw_rw_start) = (_fw_start as usize, _fw_rw_start as usize);
+    let fw_rw_size = (_fw_end as usize - fw_rw_start + 0xFFF) & !0xFFF;
+    pmp::register("fw-text", fw_start, fw_rw_start - fw_start, pmp::Owner::M, pmp::R | pmp::X);
+    pmp::register("fw-data", fw_rw_start, fw_rw_size, pmp::Owner::M, pmp::R | pmp::W);
+    // Linux may edit the FDT in place during early boot, but never executes it
+    pmp::register("fdt", fdt_addr as usize, unsafe { FDT_CAPACITY }, pmp::Owner::S, pmp::R | pmp::W);
+    if pmp::smepmp() {
+        // M-mode still needs the devices below the DRAM
+        pmp::register("mmio", 0, fw_start, pmp::Owner::Shared, pmp::R | pmp::W);
+    }
+
+    // Placed before the payload, which may overwrite the embedded initrd
+    let initrd = chosen::place_initrd(fdt_addr as usize);
+
+    // An embedded payload takes precedence over the one described by fw_dynamic_info
+    let (next_addr, next_mode, kernel) = match dynamic.filter(|_| !payload::HAS_PAYLOAD) {
+        Some(next) => {
+            crate::mprintln!("FW_DYNAMIC: next stage at 0x{:016X}", next.addr).unwrap();
+            (next.addr, next.mode, None)
+        }
+        None => {
+            // FW_JUMP, relocate payload. Both the initrd and the FDT are placed above it.
+            let limit = initrd.map(|(start, _)| start).unwrap_or(fdt_addr as usize);
+            let loaded = payload::relocate(payload_addr, limit);
+
+            (loaded.entry, riscv::register::mstatus::MPP::Supervisor, loaded.reserve)
+        }
+    };
+
+    // Other harts parse the FDT during warm boot, so they must check in before it's edited
+    cpus::check_in();
+    WARM_BOOT_FIRE.store(true, Ordering::Release);
+    cpus::wait_check_in();
+
     // Fixup fdt
-    fixup_fdt(fdt_addr);
+    fixup_fdt(fdt_addr, kernel, initrd);
 
-    // Relocate payload
-    payload::relocate(payload_addr);
+    // With Smepmp, M-mode loses access to S-mode memory from here on
+    pmp::setup(true);
 
     crate::mprintln!("Hart {} cold boot... arg1: 0x{:016x}", hartid, fdt_addr as usize).unwrap();
-    WARM_BOOT_FIRE.store(true, Ordering::Release);
+    mem::data(hartid).hsm_set(hsm::HartState::Started);
 
-    next_boot(hartid, fdt_addr);
+    next_boot(hartid, next_addr, next_mode, fdt_addr as usize);
 }
 
 fn warm_boot(hartid: usize) -> ! {
@@ -110,6 +174,13 @@ fn warm_boot(hartid: usize) -> ! {
         spin_loop_hint();
     }
 
+    if !cpus::begin_check_in() {
+        crate::mprintln!("Hart {} is not enabled in FDT or checked in too late, parking", hartid).unwrap();
+        loop {
+            unsafe { riscv::asm::wfi() };
+        }
+    }
+
     let fdt_addr = unsafe { FDT_RELOCATED_ADDR };
 
     let fdt = unsafe { fdt::FDT::from_raw(fdt_addr) }.unwrap();
@@ -119,94 +190,88 @@ fn warm_boot(hartid: usize) -> ! {
             PLATFORM::new(hartid, fdt),
         );
     }
+    mem::data(hartid).platform().early_init(false);
 
-    setup_pmp();
+    pmu::hart_init();
     trap::setup();
+    cpus::check_in();
+    pmp::setup(false);
 
-    crate::mprintln!("Hart {} warm boot... arg1: 0x{:016x}", hartid, fdt_addr as usize).unwrap();
-    next_boot(hartid, fdt_addr);
+    crate::mprintln!("Hart {} warm boot, waiting for hart_start", hartid).unwrap();
+    hsm::park(hartid);
 }
 
-// FW_JUMP mode
-fn next_boot(hartid: usize, fdt_addr: *const u8) -> ! {
+fn next_boot(hartid: usize, next_addr: usize, next_mode: riscv::register::mstatus::MPP, arg1: usize) -> ! {
     unsafe {
-        riscv::register::stvec::write(payload::PAYLOAD_TARGET as usize, riscv::register::stvec::TrapMode::Direct);
+        riscv::register::stvec::write(next_addr, riscv::register::stvec::TrapMode::Direct);
         riscv::register::sscratch::write(0);
         riscv::register::sie::clear_sext();
         riscv::register::sie::clear_ssoft();
         riscv::register::sie::clear_stimer();
         riscv::register::satp::write(0);
 
-        riscv::register::mstatus::set_mpp(riscv::register::mstatus::MPP::Supervisor);
-        riscv::register::mepc::write(payload::PAYLOAD_TARGET as usize);
-        trap::next_ret(hartid, fdt_addr);
+        riscv::register::mstatus::set_mpp(next_mode);
+        riscv::register::mepc::write(next_addr);
+        trap::next_ret(hartid, arg1);
     }
 }
 
-// static mut FDT_STORAGE: [u8; 16384] = [0; 16384];
-const FDT_STORAGE_START: *mut u8 = 0x80700000usize as _;
+// Returns the end of the /memory range containing the firmware
+fn memory_end() -> Option<usize> {
+    let fw_start = _fw_start as usize;
+    mem::memory()
+        .find(|(base, size)| *base <= fw_start && fw_start - base < *size)
+        .map(|(base, size)| base + size)
+}
 
+/**
+ * Moves the FDT to the top of the memory range containing the firmware,
+ * leaving FDT_SLACK bytes after it to grow into during fixups
+ */
 fn relocate_fdt(original: *const u8) -> *mut u8 {
     let parsed = unsafe { fdt::FDT::from_raw(original) }.unwrap();
-    let size = parsed.total_size();
+    let size = parsed.total_size() as usize;
+    let capacity = size + FDT_SLACK;
+
+    mem::probe_memory(&parsed);
+    let end = match memory_end() {
+        Some(end) => end,
+        None => {
+            crate::serial::early_print("No memory node containing the firmware\n");
+            panic!();
+        }
+    };
+
+    let target = (end - capacity) & !0xFFF;
+    if target < _fw_end as usize {
+        crate::serial::early_print("Insufficient memory for the FDT\n");
+        panic!();
+    }
+
+    // The original FDT may also sit at the top of the memory, so they may overlap
     unsafe {
-        core::ptr::copy_nonoverlapping(original, FDT_STORAGE_START, size as usize);
+        core::ptr::copy(original, target as *mut u8, size);
+        FDT_CAPACITY = end - target;
     }
 
-    FDT_STORAGE_START
+    target as *mut u8
 }
 
-fn fixup_fdt(fdt: *mut u8) {
-    // Load rsvmap offset
-    let rvsmap_offset = u32::from_be_bytes(unsafe { *(fdt.offset(16) as *const [u8; 4]) });
-    let struct_offset = u32::from_be_bytes(unsafe { *(fdt.offset(8) as *const [u8; 4]) });
-    let rvsmap_raw = unsafe { fdt.offset(rvsmap_offset as isize) };
-
-    // Find first pair of zeros
-    for i in 0.. { // FIXME: limit to fdt size
-        let addr = unsafe { u32::from_be_bytes(*(rvsmap_raw.offset(i*8) as *mut [u8;4])) };
-        let len = unsafe { u32::from_be_bytes(*(rvsmap_raw.offset(i*8+4) as *mut [u8;4])) };
-
-        if addr == 0 && len == 0 {
-            // Self is empty
-            if (i as u32 + 1) * 8 + rvsmap_offset == struct_offset {
-                crate::mprintln!("Insufficient space for additional memory reservation entry. Struct offset at {}, rvs offset at {}", struct_offset, rvsmap_offset).unwrap();
-                panic!();
-            }
-
-            // Clear next entry
-            unsafe { *(rvsmap_raw.offset(i*8 + 8) as *mut u64) = 0 };
-
-            // Fill in current entry
-            unsafe {
-                *(rvsmap_raw.offset(i*8) as *mut [u8;4]) = u32::to_be_bytes(0x80000000);
-                *(rvsmap_raw.offset(i*8+4) as *mut [u8;4]) = u32::to_be_bytes(0x200000);
-            }
-
-            break;
-        } else {
-            crate::mprintln!("Get reservation entry: 0x{:08X}, len: 0x{:08X}", addr, len).unwrap();
-        }
+fn fixup_fdt(fdt: *mut u8, kernel: Option<(usize, usize)>, initrd: Option<(usize, usize)>) {
+    let mut editor = unsafe { fdt_mut::FDTMut::new(fdt, FDT_CAPACITY) }.unwrap();
+
+    // M-mode only memory, as protected by PMP
+    for region in pmp::regions().filter(|r| r.owner == pmp::Owner::M) {
+        reserved::add_no_map(&mut editor, region.base, region.size).unwrap();
+    }
+
+    // The FDT itself, S-mode still needs to read it
+    editor.add_memreserve(fdt as u64, unsafe { FDT_CAPACITY } as u64).unwrap();
+
+    if let Some((addr, len)) = kernel {
+        editor.add_memreserve(addr as u64, len as u64).unwrap();
     }
-}
 
-fn setup_pmp() {
-    // Setup PMP for firmware itself
-    let fw_start = 0x8000_0000;
-    let fw_size = (_fw_end as usize - _fw_start as usize).next_power_of_two();
-
-    let addr_encoded = (fw_start >> 2) | ((fw_size >> 3) - 1);
-
-    crate::mprintln!("FW size: {:016X}", fw_size).unwrap();
-    crate::mprintln!("PMP encoded: {:016X}", addr_encoded).unwrap();
-
-    riscv::register::pmpaddr0::write(addr_encoded);
-    riscv::register::pmpaddr1::write((1 << (56-3)) - 1); // Maximum range
-    riscv::register::pmpcfg0::write(
-        (
-            (3 << 3) | 0 // NAPOT + not locking
-        ) | (
-            ((3 << 3) | 7) << 8
-        )
-    );
+    chosen::fixup(&mut editor, initrd).unwrap();
+    cpus::fixup(&mut editor).unwrap();
 }
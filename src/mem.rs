@@ -1,19 +1,46 @@
+use crate::hsm::HartState;
 use crate::ipi::*;
 use core::cell::UnsafeCell;
 use core::mem::MaybeUninit;
 use core::sync::atomic::*;
 
 pub struct HartData {
-    pub ipi_req: UnsafeCell<IPIReq>,
-    pub ipi_pending: AtomicBool,
+    pub ipi_pending: AtomicUsize, // Bitmap of IPIReq
+    pub ipi_seq: AtomicUsize,     // Odd while handling
+    pub ipi_fence: UnsafeCell<[Option<Fence>; FENCE_KINDS]>,
+    pub ipi_fence_lock: AtomicBool,
+    pub hsm_state: AtomicUsize,
+    pub hsm_next: UnsafeCell<(usize, usize)>,
+    pub hsm_pending: AtomicBool,
+    pub pmu: crate::pmu::HartPMU,
+    pub mprv_active: bool,
+    pub mprv_fault: Option<crate::trap::mprv::Fault>,
+    pub mprv_faulted: usize, // Non-zero once mprv_fault is recorded, for asm to branch on
+    pub checked_in: AtomicUsize, // One of cpus::CHECK_IN_*
+    pub misa: usize,
+    pub satp_mode: Option<usize>,
+    pub sstc: bool,
     pub platform: MaybeUninit<crate::PLATFORM>,
 }
 
 impl HartData {
     pub const fn new() -> Self {
         HartData {
-            ipi_req: UnsafeCell::new(IPIReq::S_IPI), // Random value
-            ipi_pending: AtomicBool::new(false),
+            ipi_pending: AtomicUsize::new(0),
+            ipi_seq: AtomicUsize::new(0),
+            ipi_fence: UnsafeCell::new([None; FENCE_KINDS]),
+            ipi_fence_lock: AtomicBool::new(false),
+            hsm_state: AtomicUsize::new(HartState::Stopped as usize),
+            hsm_next: UnsafeCell::new((0, 0)),
+            hsm_pending: AtomicBool::new(false),
+            pmu: crate::pmu::HartPMU::new(),
+            mprv_active: false,
+            mprv_fault: None,
+            mprv_faulted: 0,
+            checked_in: AtomicUsize::new(0),
+            misa: 0,
+            satp_mode: None,
+            sstc: false,
             platform: MaybeUninit::uninit(),
         }
     }
@@ -23,29 +50,65 @@ impl HartData {
         unsafe { &mut *self.platform.as_mut_ptr() }
     }
 
-    pub fn ipi_set(&self, req: IPIReq) {
-        unsafe { *self.ipi_req.get() = req };
-
-        self.ipi_pending.store(true, Ordering::Release);
+    // Posts a request, returns the ipi_seq value which acknowledges it
+    pub fn ipi_post(&self, req: IPIReq) -> usize {
+        if let Some((slot, fence)) = req.fence() {
+            self.ipi_fence_with(|slots| slots[slot] = Some(slots[slot].map_or(fence, |f| f.merge(fence))));
+        }
+        crate::ipi::fetch_or(&self.ipi_pending, req.bit());
+        crate::ipi::ipi_done(self.ipi_seq.load(Ordering::SeqCst))
     }
 
-    pub fn ipi_wait(&self) {
-        while self.ipi_pending.load(Ordering::Acquire) {
+    fn ipi_fence_with<T>(&self, f: impl FnOnce(&mut [Option<Fence>; FENCE_KINDS]) -> T) -> T {
+        while self.ipi_fence_lock.compare_and_swap(false, true, Ordering::Acquire) {
             spin_loop_hint();
         }
+
+        let ret = f(unsafe { &mut *self.ipi_fence.get() });
+        self.ipi_fence_lock.store(false, Ordering::Release);
+        ret
     }
 
     pub fn ipi_handle(&mut self) {
         use crate::platform::PlatformOps;
 
-        while self.ipi_pending.load(Ordering::Acquire) == false {
-            spin_loop_hint();
-        }
-
-        let req = unsafe { *self.ipi_req.get() };
-        crate::ipi::handle_ipi(req);
+        // Cleared first, so that requests posted during this pass trigger another one
         self.platform().clear_ipi();
-        self.ipi_pending.store(false, Ordering::Release);
+
+        // Only this hart writes ipi_seq
+        let seq = self.ipi_seq.load(Ordering::Relaxed);
+        self.ipi_seq.store(seq + 1, Ordering::SeqCst);
+        let pending = crate::ipi::take(&self.ipi_pending);
+        let fences = self.ipi_fence_with(|slots| core::mem::replace(slots, [None; FENCE_KINDS]));
+        crate::ipi::handle_pending(pending, fences);
+        self.ipi_seq.store(seq + 2, Ordering::Release);
+    }
+
+    pub fn hsm_state(&self) -> HartState {
+        unsafe { core::mem::transmute(self.hsm_state.load(Ordering::Acquire)) }
+    }
+
+    pub fn hsm_set(&self, state: HartState) {
+        self.hsm_state.store(state as usize, Ordering::Release);
+    }
+
+    pub fn hsm_transit(&self, from: HartState, to: HartState) -> bool {
+        self.hsm_state.compare_and_swap(from as usize, to as usize, Ordering::AcqRel) == from as usize
+    }
+
+    // Next entry point and opaque argument, consumed by hsm::park
+    pub fn hsm_set_next(&self, addr: usize, opaque: usize) {
+        unsafe { *self.hsm_next.get() = (addr, opaque) };
+
+        self.hsm_pending.store(true, Ordering::Release);
+    }
+
+    pub fn hsm_take(&self) -> Option<(usize, usize)> {
+        if self.hsm_pending.compare_and_swap(true, false, Ordering::Acquire) {
+            Some(unsafe { *self.hsm_next.get() })
+        } else {
+            None
+        }
     }
 }
 
@@ -76,26 +139,182 @@ impl<const STORE_SIZE: usize> HartStorage<STORE_SIZE> {
     }
 }
 
-type AllStorage = [HartStorage<{ crate::HART_STORE_SIZE }>; crate::HART_CNT];
+type AllStorage = [HartStorage<{ crate::HART_STORE_SIZE }>; crate::HART_MAX];
 
 #[link_section = ".data"]
-pub static mut STORAGE: AllStorage = [HartStorage::new(); crate::HART_CNT];
+pub static mut STORAGE: AllStorage = [HartStorage::new(); crate::HART_MAX];
 
 /*
 fn _assert_storage_size() {
     unsafe {
-        core::mem::transmute::<AllStorage, [u8; crate::HART_STORE_SIZE * crate::HART_CNT]>(
-            [HartStorage::new(); crate::HART_CNT],
+        core::mem::transmute::<AllStorage, [u8; crate::HART_STORE_SIZE * crate::HART_MAX]>(
+            [HartStorage::new(); crate::HART_MAX],
         );
     }
 }
 */
 
+const NO_HART: usize = core::usize::MAX;
+
+/**
+ * Hartid of each storage slot
+ *
+ * Slots are handed out in boot::setup_stack, in the order that harts enter MeowSBI.
+ * Harts entering after all slots are taken are parked right there.
+ */
+#[link_section = ".sdata"]
+pub static HART_IDS: [AtomicUsize; crate::HART_MAX] = [AtomicUsize::new(NO_HART); crate::HART_MAX];
+#[link_section = ".sdata"]
+pub static HART_NEXT: AtomicUsize = AtomicUsize::new(0);
+
+// Harts listed in /cpus with status = "okay", filled during cold boot
+static mut FDT_HARTS: [usize; crate::HART_MAX] = [NO_HART; crate::HART_MAX];
+
+pub fn probe_harts(fdt: &fdt::FDT) {
+    let mut cnt = 0;
+
+    for node in fdt.nodes() {
+        if !node.property("device_type").map(|p| p.raw() == b"cpu\0").unwrap_or(false) {
+            continue;
+        }
+
+        // Missing status means okay
+        let okay = node
+            .property("status")
+            .map(|p| p.raw() == b"okay\0" || p.raw() == b"ok\0")
+            .unwrap_or(true);
+        let hartid = match node.property("reg").and_then(|p| p.as_u32().ok()) {
+            Some(reg) if okay => reg as usize,
+            _ => continue,
+        };
+
+        if cnt == crate::HART_MAX {
+            crate::mprintln!("Hart {} exceeds HART_MAX, ignored", hartid).unwrap();
+            continue;
+        }
+
+        unsafe { FDT_HARTS[cnt] = hartid };
+        cnt += 1;
+    }
+
+    crate::mprintln!("Harts: {:?}", unsafe { &FDT_HARTS[..cnt] }).unwrap();
+}
+
+pub fn enabled_harts() -> impl Iterator<Item = usize> {
+    unsafe { FDT_HARTS.iter().cloned().filter(|h| *h != NO_HART) }
+}
+
+pub fn hart_enabled(hartid: usize) -> bool {
+    hartid != NO_HART && unsafe { FDT_HARTS.iter().any(|h| *h == hartid) }
+}
+
+// Removes a hart from the system, e.g. when it never checked in
+pub fn disable_hart(hartid: usize) {
+    unsafe {
+        for h in FDT_HARTS.iter_mut().filter(|h| **h == hartid) {
+            core::ptr::write_volatile(h, NO_HART);
+        }
+    }
+    fence(Ordering::SeqCst);
+}
+
+pub fn hart_index(hartid: usize) -> Option<usize> {
+    HART_IDS.iter().position(|h| h.load(Ordering::Acquire) == hartid)
+}
+
+// Should only be called with hartids that already entered MeowSBI
 pub fn data(hartid: usize) -> &'static mut HartData {
-    unsafe { &mut STORAGE[hartid].data }
+    let idx = hart_index(hartid).expect("Hart not present");
+    unsafe { &mut STORAGE[idx].data }
+}
+
+// Returns None for harts that are not part of the system
+pub fn try_data(hartid: usize) -> Option<&'static mut HartData> {
+    if !hart_enabled(hartid) {
+        return None;
+    }
+
+    hart_index(hartid).map(|idx| unsafe { &mut STORAGE[idx].data })
 }
 
 pub fn local_data() -> &'static mut HartData {
     let hid = riscv::register::mhartid::read();
     data(hid)
 }
+
+pub fn in_firmware(addr: usize) -> bool {
+    addr >= crate::_fw_start as usize && addr < crate::_fw_end as usize
+}
+
+const MEMORY_MAX: usize = 8;
+
+// (base, size) of each /memory range, filled during cold boot
+static mut MEMORY: [(usize, usize); MEMORY_MAX] = [(0, 0); MEMORY_MAX];
+
+pub fn probe_memory(fdt: &fdt::FDT) {
+    let root = match fdt.nodes().next() {
+        Some(root) => root,
+        None => return,
+    };
+    let cells = |name: &str| root.property(name).and_then(|p| p.as_u32().ok()).unwrap_or(2) as usize;
+    let (addr_cells, size_cells) = (cells("#address-cells"), cells("#size-cells"));
+
+    let read = |raw: &[u8], cells: usize| {
+        raw[..cells * 4].iter().fold(0usize, |acc, b| (acc << 8) | *b as usize)
+    };
+
+    let mut cnt = 0;
+    for node in fdt.nodes() {
+        if !node.property("device_type").map(|p| p.raw() == b"memory\0").unwrap_or(false) {
+            continue;
+        }
+
+        let reg = match node.property("reg") {
+            Some(reg) => reg.raw(),
+            None => continue,
+        };
+
+        for entry in reg.chunks_exact((addr_cells + size_cells) * 4) {
+            if cnt == MEMORY_MAX {
+                return;
+            }
+
+            let base = read(entry, addr_cells);
+            let size = read(&entry[addr_cells * 4..], size_cells);
+            unsafe { MEMORY[cnt] = (base, size) };
+            cnt += 1;
+        }
+    }
+}
+
+pub fn memory() -> impl Iterator<Item = (usize, usize)> {
+    unsafe { MEMORY.iter().cloned().filter(|(_, size)| *size != 0) }
+}
+
+/**
+ * Checks if a physical range given by S-mode lies within memory, and is accessible by S-mode
+ * with all of the given PMP permissions
+ */
+pub fn s_accessible(base: usize, len: usize, perm: u8) -> bool {
+    let end = match base.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+
+    if len == 0 {
+        return true;
+    }
+
+    // Firmware regions are checked regardless of whether they have been registered yet
+    if end > crate::_fw_start as usize && base < crate::_fw_end as usize {
+        return false;
+    }
+
+    if !memory().any(|(mem_base, mem_size)| base >= mem_base && end - mem_base <= mem_size) {
+        return false;
+    }
+
+    crate::pmp::regions()
+        .filter(|r| base < r.base + r.size && r.base < end)
+        .all(|r| r.owner != crate::pmp::Owner::M && r.perm & perm == perm)
+}
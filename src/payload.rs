@@ -8,18 +8,277 @@ include!(concat!(env!("OUT_DIR"), "/payload_content.rs"));
 pub const HAS_PAYLOAD: bool = cfg!(feature = "payload");
 pub const PAYLOAD_TARGET: *mut u8 = 0x80200000usize as _;
 
-pub fn relocate(payload_addr: *const u8) {
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const EM_RISCV: u16 = 0xF3;
+const PT_LOAD: u32 = 1;
+const MAX_SEGMENTS: usize = 16;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+struct ElfHeader {
+    ident: [u8; 16],
+    etype: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+#[allow(dead_code)]
+struct ProgramHeader {
+    ptype: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+// Linux RISC-V Image header, see Documentation/riscv/boot-image-header.rst
+#[repr(C)]
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+struct ImageHeader {
+    code0: u32,
+    code1: u32,
+    text_offset: u64,
+    image_size: u64,
+    flags: u64,
+    version: u32,
+    res1: u32,
+    res2: u64,
+    magic: [u8; 8],  // Deprecated
+    magic2: [u8; 4],
+    res3: u32,
+}
+
+const IMAGE_MAGIC: [u8; 8] = *b"RISCV\0\0\0";
+const IMAGE_MAGIC2: [u8; 4] = *b"RSC\x05";
+
+// Placement of the payload in memory, the kernel image is reserved in the FDT
+pub struct Loaded {
+    pub entry: usize,
+    pub reserve: Option<(usize, usize)>,
+}
+
+// Whether [base, base + size) may receive the payload, checked before anything is copied
+fn fits(base: usize, size: usize, limit: usize) -> bool {
+    base.checked_add(size).map_or(false, |end| end <= limit) && crate::mem::s_accessible(base, size, crate::pmp::W)
+}
+
+fn image_header(image: *const u8) -> Option<ImageHeader> {
+    let header = unsafe { core::ptr::read_unaligned(image as *const ImageHeader) };
+    if header.magic2 == IMAGE_MAGIC2 || header.magic == IMAGE_MAGIC {
+        Some(header)
+    } else {
+        None
+    }
+}
+
+// Places a Linux Image at DRAM base + text_offset. The firmware itself sits at the DRAM base.
+fn load_image(image: *const u8, header: ImageHeader, file_size: usize, limit: usize) -> Loaded {
+    let target = crate::_fw_start as usize + header.text_offset as usize;
+    let size = core::cmp::max(header.image_size as usize, file_size);
+
+    if !fits(target, size, limit) {
+        crate::mprintln!("Linux Image at 0x{:016X}, size 0x{:X} overlaps with firmware, initrd or FDT", target, size).unwrap();
+        panic!();
+    }
+
+    if image as usize != target {
+        crate::mprintln!("Linux Image relocation 0x{:016X} -> 0x{:016X}", image as usize, target).unwrap();
+        unsafe { core::ptr::copy(image, target as *mut u8, file_size) };
+    }
+
+    crate::mprintln!("Linux Image at 0x{:016X}, size 0x{:X}", target, size).unwrap();
+    Loaded {
+        entry: target,
+        reserve: Some((target, size)),
+    }
+}
+
+fn is_elf(image: *const u8) -> bool {
+    unsafe { core::ptr::read_unaligned(image as *const [u8; 4]) == ELF_MAGIC }
+}
+
+fn overlaps(a: usize, a_len: usize, b: usize, b_len: usize) -> bool {
+    a < b + b_len && b < a + a_len
+}
+
+/**
+ * Loads an ELF64 image of image_size bytes (None if unknown) below limit, returns its physical entry point
+ *
+ * The image may overlap with its own segments (e.g. an ELF placed at PAYLOAD_TARGET),
+ * so all program headers are read before anything is copied. A segment is only copied once its
+ * destination no longer holds the source of another pending segment, and BSS is zeroed last.
+ */
+fn load_elf(image: *const u8, image_size: Option<usize>, limit: usize) -> Result<usize, &'static str> {
+    let header = unsafe { core::ptr::read_unaligned(image as *const ElfHeader) };
+
+    if header.ident[4] != 2 || header.ident[5] != 1 {
+        return Err("not a little-endian ELF64");
+    }
+    if header.machine != EM_RISCV {
+        return Err("not a RISC-V executable");
+    }
+    if header.phentsize as usize != core::mem::size_of::<ProgramHeader>() {
+        return Err("unexpected program header size");
+    }
+
+    let mut segments = [ProgramHeader::default(); MAX_SEGMENTS];
+    let mut cnt = 0;
+
+    for i in 0..header.phnum as usize {
+        let ph = unsafe {
+            let ptr = image.offset((header.phoff as usize + i * header.phentsize as usize) as isize);
+            core::ptr::read_unaligned(ptr as *const ProgramHeader)
+        };
+
+        if ph.ptype != PT_LOAD || ph.memsz == 0 {
+            continue;
+        }
+        if ph.filesz > ph.memsz {
+            return Err("segment file size exceeds memory size");
+        }
+        match ph.offset.checked_add(ph.filesz) {
+            Some(end) if image_size.map_or(true, |size| end as usize <= size) => {}
+            _ => return Err("segment exceeds the image"),
+        }
+        if !fits(ph.paddr as usize, ph.memsz as usize, limit) {
+            return Err("segment overlaps with firmware, initrd or FDT");
+        }
+        if cnt == MAX_SEGMENTS {
+            return Err("too many segments");
+        }
+
+        segments[cnt] = ph;
+        cnt += 1;
+    }
+
+    // e_entry is virtual, while segments are loaded by their physical address
+    let entry = segments[..cnt]
+        .iter()
+        .find(|ph| ph.vaddr <= header.entry && header.entry < ph.vaddr + ph.memsz)
+        .map(|ph| header.entry - ph.vaddr + ph.paddr)
+        .ok_or("entry point is not in any segment")?;
+
+    let source = |ph: &ProgramHeader| image as usize + ph.offset as usize;
+    let mut pending = [true; MAX_SEGMENTS];
+
+    for _ in 0..cnt {
+        let next = (0..cnt).find(|&i| {
+            let ph = &segments[i];
+            pending[i]
+                && (0..cnt).all(|j| {
+                    let other = &segments[j];
+                    j == i
+                        || !pending[j]
+                        || !overlaps(ph.paddr as usize, ph.filesz as usize, source(other), other.filesz as usize)
+                })
+        });
+
+        let i = match next {
+            Some(i) => i,
+            None => return Err("segments overwrite each other's contents"),
+        };
+        let ph = &segments[i];
+
+        crate::mprintln!(
+            "Loading segment 0x{:016X} -> 0x{:016X}, size 0x{:X}",
+            ph.offset,
+            ph.paddr,
+            ph.memsz
+        )
+        .unwrap();
+
+        unsafe {
+            core::ptr::copy(source(ph) as *const u8, ph.paddr as *mut u8, ph.filesz as usize);
+        }
+        pending[i] = false;
+    }
+
+    for ph in &segments[..cnt] {
+        unsafe {
+            core::ptr::write_bytes(
+                (ph.paddr + ph.filesz) as *mut u8,
+                0,
+                (ph.memsz - ph.filesz) as usize,
+            );
+        }
+    }
+
+    Ok(entry as usize)
+}
+
+fn load_elf_or_halt(image: *const u8, image_size: Option<usize>, limit: usize) -> Loaded {
+    match load_elf(image, image_size, limit) {
+        Ok(entry) => {
+            crate::mprintln!("ELF payload loaded, entry at 0x{:016X}", entry).unwrap();
+            Loaded { entry, reserve: None }
+        }
+        Err(e) => {
+            crate::mprintln!("Invalid ELF payload: {}", e).unwrap();
+            panic!();
+        }
+    }
+}
+
+// Places the payload in memory, entirely below limit
+pub fn relocate(payload_addr: *const u8, limit: usize) -> Loaded {
+    let raw = Loaded {
+        entry: PAYLOAD_TARGET as usize,
+        reserve: None,
+    };
+
     if !HAS_PAYLOAD {
+        // ELF images and Linux Images may be placed by the loader at PAYLOAD_TARGET
+        if is_elf(PAYLOAD_TARGET) {
+            return load_elf_or_halt(PAYLOAD_TARGET, None, limit);
+        } else if let Some(header) = image_header(PAYLOAD_TARGET) {
+            return load_image(PAYLOAD_TARGET, header, header.image_size as usize, limit);
+        }
+
+        // Size unknown
         crate::mprintln!("MeowSBI built without payload, skipping payload relocation").unwrap();
-        return;
-    } else if payload_addr == PAYLOAD_TARGET {
+        return raw;
+    }
+
+    let payload_size = _payload_end as usize - _payload_start as usize;
+
+    if is_elf(payload_addr) {
+        return load_elf_or_halt(payload_addr, Some(payload_size), limit);
+    } else if let Some(header) = image_header(payload_addr) {
+        return load_image(payload_addr, header, payload_size, limit);
+    }
+
+    if !fits(PAYLOAD_TARGET as usize, payload_size, limit) {
+        crate::mprintln!("Payload of size 0x{:X} overlaps with firmware, initrd or FDT", payload_size).unwrap();
+        panic!();
+    }
+
+    if payload_addr == PAYLOAD_TARGET {
         crate::mprintln!("Payload already at 0x{:016X}, skipping relocation", payload_addr as usize).unwrap();
-        return;
+        return raw;
     }
 
     crate::mprintln!("Payload relocation 0x{:016X} -> 0x{:016X}", payload_addr as usize, PAYLOAD_TARGET as usize).unwrap();
     unsafe {
-        core::ptr::copy(payload_addr, PAYLOAD_TARGET, _payload_end as usize - _payload_start as usize);
+        core::ptr::copy(payload_addr, PAYLOAD_TARGET, payload_size);
     }
     crate::mprintln!("Relocation complete").unwrap();
+
+    raw
 }
@@ -0,0 +1,275 @@
+use super::{PlatformOps, ResetReason, ResetType};
+use crate::utils::clint::CLINT;
+use crate::utils::plic::PLIC;
+use crate::utils::sifive_test::SiFiveTest;
+use crate::utils::syscon::SysConReg;
+use crate::utils::uart::UART16550;
+
+/**
+ * Board-specific fallbacks, for devices missing in the FDT
+ */
+pub struct Defaults {
+    pub uart: usize,
+    pub uart_shift: usize,
+    pub uart_clk: usize,
+    pub uart_baud: usize,
+    pub clint: usize,
+    pub test: Option<usize>,
+}
+
+const GENERIC_DEFAULTS: Defaults = Defaults {
+    uart: 0x10000000,
+    uart_shift: 0,
+    uart_clk: 11_059_200,
+    uart_baud: 115200,
+    clint: 0x2000000,
+    test: None,
+};
+
+/**
+ * FDT-driven platform with a CLINT, an optional PLIC and a 16550 UART
+ */
+pub struct Generic {
+    serial: UART16550,
+    clint: CLINT,
+    plic: Option<PLIC>,
+    test: Option<SiFiveTest>,
+    poweroff: Option<SysConReg>,
+    reboot: Option<SysConReg>,
+}
+
+impl Generic {
+    pub fn probe(hartid: usize, fdt: fdt::FDT, defaults: &Defaults) -> Self {
+        use core::convert::TryInto;
+        let mut clint_addr = None;
+        let mut uart_addr = None;
+        let mut uart_shift: Option<usize> = None;
+        let mut uart_offset: Option<usize> = None;
+        let mut uart_clk: Option<usize> = None;
+        let mut uart_baud: Option<usize> = None;
+        let mut test_addr = None;
+        // (regmap, offset, value, mask)
+        let mut poweroff_desc: Option<(u32, u32, u32, u32)> = None;
+        let mut reboot_desc: Option<(u32, u32, u32, u32)> = None;
+        let mut plic_addr = None;
+        let mut plic_ndev = 0;
+        let mut plic_contexts: &[u8] = &[];
+        // (depth, hartid) of the cpu node we are currently in
+        let mut cur_cpu: Option<(usize, usize)> = None;
+        let mut intc_phandle = None;
+
+        crate::serial::early_print("Parsing FDT\n");
+        for node in fdt.nodes() {
+            if let Some((depth, _)) = cur_cpu {
+                if node.depth() <= depth {
+                    cur_cpu = None;
+                }
+            }
+
+            if node.property("device_type").map(|p| p.raw() == b"cpu\0").unwrap_or(false) {
+                let reg = node.property("reg").and_then(|p| p.as_u32().ok());
+                cur_cpu = reg.map(|r| (node.depth(), r as usize));
+            } else if let Some((_, cpu)) = cur_cpu {
+                // Local interrupt controller of this hart
+                if cpu == hartid && node.property("interrupt-controller").is_some() {
+                    intc_phandle = node.phandle();
+                }
+            } else if node.is_compatible_with("riscv,plic0") || node.is_compatible_with("sifive,plic-1.0.0") {
+                let addr = node
+                    .property("reg")
+                    .map(|p| p.raw().split_at(core::mem::size_of::<usize>()).0);
+                plic_addr = addr.map(|p| usize::from_be_bytes(p.try_into().unwrap()));
+
+                plic_ndev = node.property("riscv,ndev")
+                    .and_then(|p| p.as_u32().ok())
+                    .unwrap_or(0) as usize;
+
+                plic_contexts = node.property("interrupts-extended")
+                    .map(|p| p.raw())
+                    .unwrap_or(&[]);
+            } else if node.is_compatible_with("clint0")
+                || node.is_compatible_with("riscv,clint0")
+                || node.is_compatible_with("sifive,clint0")
+            {
+                let addr = node
+                    .property("reg")
+                    .map(|p| p.raw().split_at(core::mem::size_of::<usize>()).0);
+                clint_addr = addr.map(|p| usize::from_be_bytes(p.try_into().unwrap()));
+            } else if node.is_compatible_with("ns16550a") || node.is_compatible_with("na16550"){
+                let addr = node
+                    .property("reg")
+                    .map(|p| p.raw().split_at(core::mem::size_of::<usize>()).0);
+                uart_addr = addr.map(|p| usize::from_be_bytes(p.try_into().unwrap()));
+
+                uart_offset = node.property("reg-offset")
+                    .and_then(|p| p.as_u32().ok())
+                    .map(|r| r as _);
+
+                uart_shift = node.property("reg-shift")
+                    .and_then(|p| p.as_u32().ok())
+                    .map(|r| r as _);
+
+                uart_clk = node.property("clock-frequency")
+                    .and_then(|p| p.as_u32().ok())
+                    .map(|r| r as _);
+
+                uart_baud = node.property("current-speed")
+                    .and_then(|p| p.as_u32().ok())
+                    .map(|r| r as _);
+            } else if node.is_compatible_with("sifive,test0") {
+                let addr = node
+                    .property("reg")
+                    .map(|p| p.raw().split_at(core::mem::size_of::<usize>()).0);
+                test_addr = addr.map(|p| usize::from_be_bytes(p.try_into().unwrap()));
+            } else if node.is_compatible_with("syscon-poweroff") || node.is_compatible_with("syscon-reboot") {
+                let regmap = node.property("regmap").and_then(|p| p.as_u32().ok());
+                let offset = node.property("offset").and_then(|p| p.as_u32().ok()).unwrap_or(0);
+                let value = node.property("value").and_then(|p| p.as_u32().ok());
+                let mask = node.property("mask").and_then(|p| p.as_u32().ok());
+
+                // Follows the Linux binding: a missing value means writing the mask
+                let (value, mask) = match (value, mask) {
+                    (Some(v), m) => (v, m.unwrap_or(core::u32::MAX)),
+                    (None, Some(m)) => (m, core::u32::MAX),
+                    (None, None) => continue,
+                };
+
+                let desc = regmap.map(|r| (r, offset, value, mask));
+                if node.is_compatible_with("syscon-poweroff") {
+                    poweroff_desc = desc;
+                } else {
+                    reboot_desc = desc;
+                }
+            }
+        }
+
+        let resolve_syscon = |desc: Option<(u32, u32, u32, u32)>| {
+            let (regmap, offset, value, mask) = desc?;
+            let syscon = fdt.phandle(regmap)?;
+            let base = syscon
+                .property("reg")
+                .map(|p| p.raw().split_at(core::mem::size_of::<usize>()).0)
+                .map(|p| usize::from_be_bytes(p.try_into().unwrap()))?;
+            Some(SysConReg::new((base + offset as usize) as *mut u8, value, mask))
+        };
+
+        // crate::serial::early_print("Parsing Finished\n");
+
+        // Each context is a <phandle, cause> pair in interrupts-extended, pointing to a hart's local intc.
+        // Without it, we assume context 2*i+1 = hart i S-mode
+        let (mut m_ctx, mut s_ctx) = (None, None);
+        for (idx, ctx) in plic_contexts.chunks_exact(8).enumerate() {
+            let phandle = u32::from_be_bytes(ctx[0..4].try_into().unwrap());
+            let cause = u32::from_be_bytes(ctx[4..8].try_into().unwrap());
+            if Some(phandle) != intc_phandle {
+                continue;
+            }
+
+            match cause {
+                11 => m_ctx = Some(idx),
+                9 => s_ctx = Some(idx),
+                _ => {}
+            }
+        }
+
+        if plic_contexts.is_empty() {
+            m_ctx = Some(hartid * 2);
+            s_ctx = Some(hartid * 2 + 1);
+        }
+
+        Generic {
+            serial: UART16550::new(
+                uart_addr.unwrap_or(defaults.uart) + uart_offset.unwrap_or(0),
+                uart_shift.unwrap_or(defaults.uart_shift),
+                uart_clk.unwrap_or(defaults.uart_clk) as _,
+                uart_baud.unwrap_or(defaults.uart_baud) as _,
+            ),
+            clint: CLINT::new(clint_addr.unwrap_or(defaults.clint) as *mut u8, hartid),
+            plic: plic_addr.map(|a| PLIC::new(a as *mut u8, plic_ndev, m_ctx, s_ctx)),
+            test: test_addr.or(defaults.test).map(|a| SiFiveTest::new(a as *mut u8)),
+            poweroff: resolve_syscon(poweroff_desc),
+            reboot: resolve_syscon(reboot_desc),
+        }
+    }
+}
+
+impl PlatformOps for Generic {
+    fn new(hartid: usize, fdt: fdt::FDT) -> Self {
+        Self::probe(hartid, fdt, &GENERIC_DEFAULTS)
+    }
+
+    fn early_init(&self, cold: bool) {
+        if cold {
+            self.clint.setup_leader();
+            self.serial.init();
+
+            if let Some(ref plic) = self.plic {
+                plic.setup_leader();
+            }
+        }
+
+        // TODO: barrier here
+        self.clint.setup();
+
+        if let Some(ref plic) = self.plic {
+            plic.setup();
+        }
+    }
+
+    fn set_timer(&self, instant: u64) {
+        self.clint.set_timer(instant);
+    }
+
+    fn read_time(&self) -> u64 {
+        self.clint.read_time()
+    }
+
+    fn put_char(&self, c: u8) {
+        self.serial.putchar(c)
+    }
+
+    fn get_char(&self) -> u8 {
+        self.serial.getchar()
+    }
+
+    fn try_get_char(&self) -> Option<u8> {
+        self.serial.try_getchar()
+    }
+
+    fn send_ipi(&self, hartid: usize) {
+        self.clint.send_soft(hartid);
+    }
+
+    fn clear_ipi(&self) {
+        self.clint.clear_soft();
+    }
+
+    fn system_reset(&self, reset_type: ResetType, reason: ResetReason) -> bool {
+        match (reset_type, &self.test) {
+            (ResetType::Shutdown, Some(test)) if reason == ResetReason::SystemFailure => {
+                test.fail(1);
+                true
+            }
+            (ResetType::Shutdown, test) => {
+                if let Some(ref poweroff) = self.poweroff {
+                    poweroff.fire();
+                } else if let Some(test) = test {
+                    test.pass();
+                } else {
+                    return false;
+                }
+                true
+            }
+            (ResetType::ColdReboot, test) | (ResetType::WarmReboot, test) => {
+                if let Some(ref reboot) = self.reboot {
+                    reboot.fire();
+                } else if let Some(test) = test {
+                    test.reset();
+                } else {
+                    return false;
+                }
+                true
+            }
+        }
+    }
+}
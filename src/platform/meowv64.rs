@@ -1 +1,15 @@
-pub type MeowV64 = super::qemu::QEMU;
+use super::generic::Defaults;
+use super::PlatformOps;
+
+// Same layout as QEMU virt, which MeowV64 used to be an alias of
+generic_board!(
+    MeowV64,
+    Defaults {
+        uart: 0x10000000,
+        uart_shift: 0,
+        uart_clk: 3_686_400,
+        uart_baud: 115200,
+        clint: 0x2000000,
+        test: Some(0x100000),
+    }
+);
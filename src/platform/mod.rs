@@ -1,16 +1,174 @@
+// Boards that only differ from the generic platform in their fallback devices
+macro_rules! generic_board {
+    ($name:ident, $defaults:expr) => {
+        pub struct $name(super::generic::Generic);
+
+        impl PlatformOps for $name {
+            fn new(hartid: usize, fdt: fdt::FDT) -> Self {
+                $name(super::generic::Generic::probe(hartid, fdt, &$defaults))
+            }
+            fn early_init(&self, cold: bool) {
+                self.0.early_init(cold)
+            }
+            fn final_init(&self, cold: bool) {
+                self.0.final_init(cold)
+            }
+            fn set_timer(&self, instant: u64) {
+                self.0.set_timer(instant)
+            }
+            fn read_time(&self) -> u64 {
+                self.0.read_time()
+            }
+            fn put_char(&self, c: u8) {
+                self.0.put_char(c)
+            }
+            fn get_char(&self) -> u8 {
+                self.0.get_char()
+            }
+            fn try_get_char(&self) -> Option<u8> {
+                self.0.try_get_char()
+            }
+            fn send_ipi(&self, hartid: usize) {
+                self.0.send_ipi(hartid)
+            }
+            fn clear_ipi(&self) {
+                self.0.clear_ipi()
+            }
+            fn system_reset(&self, reset_type: super::ResetType, reason: super::ResetReason) -> bool {
+                self.0.system_reset(reset_type, reason)
+            }
+        }
+    };
+}
+
+pub mod generic;
 pub mod meowv64;
 pub mod qemu;
 
+#[repr(usize)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+#[repr(usize)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ResetReason {
+    NoReason = 0,
+    SystemFailure = 1,
+}
+
 pub trait PlatformOps: Sized {
     fn new(hardid: usize, fdt: fdt::FDT) -> Self;
+    /**
+     * Called on every hart right after new. The cold boot hart resets mtime and re-initializes
+     * the UART described by the FDT, replacing the early print setup.
+     */
     fn early_init(&self, _cold: bool) {}
     fn final_init(&self, _cold: bool) {}
 
     fn set_timer(&self, instant: u64);
+    fn read_time(&self) -> u64;
 
     fn put_char(&self, c: u8);
     fn get_char(&self) -> u8;
+    fn try_get_char(&self) -> Option<u8>;
 
     fn send_ipi(&self, hartid: usize);
     fn clear_ipi(&self);
+
+    // Returns false if the platform has no device for this kind of reset
+    fn system_reset(&self, _reset_type: ResetType, _reason: ResetReason) -> bool {
+        false
+    }
+}
+
+/**
+ * All supported platforms, selected by the root compatible string of the FDT
+ */
+pub enum Platform {
+    MeowV64(meowv64::MeowV64),
+    QEMU(qemu::QEMU),
+    Generic(generic::Generic),
+}
+
+macro_rules! dispatch {
+    ($self:ident, $p:ident => $e:expr) => {
+        match $self {
+            Platform::MeowV64($p) => $e,
+            Platform::QEMU($p) => $e,
+            Platform::Generic($p) => $e,
+        }
+    };
+}
+
+impl Platform {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Platform::MeowV64(_) => "MeowV64",
+            Platform::QEMU(_) => "QEMU virt",
+            Platform::Generic(_) => "Generic",
+        }
+    }
+}
+
+impl PlatformOps for Platform {
+    fn new(hartid: usize, fdt: fdt::FDT) -> Self {
+        let root_compatible = |compat: &str| {
+            fdt.nodes()
+                .next()
+                .map(|root| root.is_compatible_with(compat))
+                .unwrap_or(false)
+        };
+
+        if root_compatible("riscv-meowv64") {
+            Platform::MeowV64(meowv64::MeowV64::new(hartid, fdt))
+        } else if root_compatible("riscv-virtio") {
+            Platform::QEMU(qemu::QEMU::new(hartid, fdt))
+        } else {
+            Platform::Generic(generic::Generic::new(hartid, fdt))
+        }
+    }
+
+    fn early_init(&self, cold: bool) {
+        dispatch!(self, p => p.early_init(cold))
+    }
+
+    fn final_init(&self, cold: bool) {
+        dispatch!(self, p => p.final_init(cold))
+    }
+
+    fn set_timer(&self, instant: u64) {
+        dispatch!(self, p => p.set_timer(instant))
+    }
+
+    fn read_time(&self) -> u64 {
+        dispatch!(self, p => p.read_time())
+    }
+
+    fn put_char(&self, c: u8) {
+        dispatch!(self, p => p.put_char(c))
+    }
+
+    fn get_char(&self) -> u8 {
+        dispatch!(self, p => p.get_char())
+    }
+
+    fn try_get_char(&self) -> Option<u8> {
+        dispatch!(self, p => p.try_get_char())
+    }
+
+    fn send_ipi(&self, hartid: usize) {
+        dispatch!(self, p => p.send_ipi(hartid))
+    }
+
+    fn clear_ipi(&self) {
+        dispatch!(self, p => p.clear_ipi())
+    }
+
+    fn system_reset(&self, reset_type: ResetType, reason: ResetReason) -> bool {
+        dispatch!(self, p => p.system_reset(reset_type, reason))
+    }
 }
@@ -0,0 +1,342 @@
+/**
+ * PMP manager
+ *
+ * Subsystems register regions during cold boot, which are then programmed on every hart,
+ * in registration order (earlier regions take priority). The last entry is always a catch-all
+ * region granting S/U-mode access to everything else.
+ *
+ * With Smepmp, mseccfg.MML is set, so M-mode can only access M-owned and shared regions,
+ * and never executes outside of M-owned ones. The first two entries are then reserved for
+ * temporarily mapping S-mode buffers into M-mode, see with_s_memory.
+ */
+
+pub const R: u8 = 1 << 0;
+pub const W: u8 = 1 << 1;
+pub const X: u8 = 1 << 2;
+
+const A_OFF: u8 = 0 << 3;
+const A_TOR: u8 = 1 << 3;
+const A_NAPOT: u8 = 3 << 3;
+const L: u8 = 1 << 7;
+
+const MAX_ENTRIES: usize = 64;
+const MAX_REGIONS: usize = 8;
+
+const MSECCFG_MML: usize = 1 << 0;
+const MSECCFG_MMWP: usize = 1 << 1;
+const MSECCFG_RLB: usize = 1 << 2;
+
+// Entries used by with_s_memory
+const RESV_ENTRIES: usize = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    M,
+    S,
+    Shared, // Read-write for both, never executable
+}
+
+#[derive(Clone, Copy)]
+pub struct Region {
+    pub name: &'static str,
+    pub base: usize,
+    pub size: usize,
+    pub owner: Owner,
+    pub perm: u8, // Permissions of the owner
+}
+
+static mut REGIONS: [Option<Region>; MAX_REGIONS] = [None; MAX_REGIONS];
+static mut SMEPMP: bool = false;
+static mut GRANULARITY: usize = 4;
+
+macro_rules! pmp_csrs {
+    ($($idx:literal),*) => {
+        fn addr_read(idx: usize) -> usize {
+            let val: usize;
+            match idx {
+                $($idx => unsafe {
+                    llvm_asm!(concat!("csrr $0, pmpaddr", stringify!($idx)) : "=r"(val) ::: "volatile")
+                },)*
+                _ => return 0,
+            }
+            val
+        }
+
+        fn addr_write(idx: usize, val: usize) {
+            match idx {
+                $($idx => unsafe {
+                    llvm_asm!(concat!("csrw pmpaddr", stringify!($idx), ", $0") :: "r"(val) :: "volatile")
+                },)*
+                _ => {}
+            }
+        }
+    };
+}
+
+pmp_csrs!(
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+    16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31,
+    32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47,
+    48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63
+);
+
+// On RV64, only even pmpcfg registers exist, each holding 8 entries
+fn cfg_write(reg: usize, val: usize) {
+    match reg {
+        0 => unsafe { llvm_asm!("csrw pmpcfg0, $0" :: "r"(val) :: "volatile") },
+        2 => unsafe { llvm_asm!("csrw pmpcfg2, $0" :: "r"(val) :: "volatile") },
+        4 => unsafe { llvm_asm!("csrw pmpcfg4, $0" :: "r"(val) :: "volatile") },
+        6 => unsafe { llvm_asm!("csrw pmpcfg6, $0" :: "r"(val) :: "volatile") },
+        8 => unsafe { llvm_asm!("csrw pmpcfg8, $0" :: "r"(val) :: "volatile") },
+        10 => unsafe { llvm_asm!("csrw pmpcfg10, $0" :: "r"(val) :: "volatile") },
+        12 => unsafe { llvm_asm!("csrw pmpcfg12, $0" :: "r"(val) :: "volatile") },
+        14 => unsafe { llvm_asm!("csrw pmpcfg14, $0" :: "r"(val) :: "volatile") },
+        _ => {}
+    }
+}
+
+fn mseccfg_set(bits: usize) {
+    unsafe { llvm_asm!("csrs 0x747, $0" :: "r"(bits) :: "volatile") };
+}
+
+/**
+ * Detects Smepmp on the boot hart, either from riscv,isa or by probing mseccfg
+ * Should be called before any region is registered
+ */
+pub fn detect(fdt: &fdt::FDT, hartid: usize) -> bool {
+    let in_isa = fdt.nodes().any(|node| {
+        node.property("device_type").map(|p| p.raw() == b"cpu\0").unwrap_or(false)
+            && node.property("reg").and_then(|p| p.as_u32().ok()) == Some(hartid as u32)
+            && node.property("riscv,isa")
+                .map(|p| p.raw().split(|b| *b == b'_' || *b == 0).any(|ext| ext == b"smepmp"))
+                .unwrap_or(false)
+    });
+
+    // RLB is hardwired to zero without Smepmp, and setting it is harmless.
+    // mseccfg itself may also exist for other extensions, e.g. Zkr.
+    let probed = crate::trap::mprv::guarded(|| unsafe {
+        let val: usize;
+        llvm_asm!("csrrs $0, 0x747, $1
+                   csrr $0, 0x747" : "=&r"(val) : "r"(MSECCFG_RLB) :: "volatile");
+        val
+    });
+
+    let smepmp = match probed {
+        Ok(val) => val & MSECCFG_RLB != 0 || in_isa,
+        Err(_) => false, // No mseccfg at all
+    };
+    unsafe { SMEPMP = smepmp };
+    smepmp
+}
+
+pub fn smepmp() -> bool {
+    unsafe { SMEPMP }
+}
+
+pub fn register(name: &'static str, base: usize, size: usize, owner: Owner, perm: u8) {
+    let regions = unsafe { &mut REGIONS };
+    match regions.iter_mut().find(|r| r.is_none()) {
+        Some(slot) => *slot = Some(Region { name, base, size, owner, perm }),
+        None => {
+            crate::mprintln!("Too many PMP regions, {} ignored", name).unwrap();
+        }
+    }
+}
+
+pub fn regions() -> impl Iterator<Item = &'static Region> {
+    unsafe { REGIONS.iter().filter_map(|r| r.as_ref()) }
+}
+
+/**
+ * Probes the number of entries and the granularity, through WARL writes
+ *
+ * Unimplemented entries are hardwired to zero. Entries above 16 may not exist as CSRs
+ * on older cores, so these accesses are guarded against illegal instruction traps.
+ * Must be called after trap::setup.
+ */
+pub fn probe() -> (usize, usize) {
+    for reg in (0..MAX_ENTRIES / 4).step_by(2) {
+        let _ = crate::trap::mprv::guarded(|| cfg_write(reg, 0));
+    }
+
+    let mut count = 0;
+    while count < MAX_ENTRIES {
+        let idx = count;
+        let implemented = crate::trap::mprv::guarded(|| {
+            addr_write(idx, core::usize::MAX);
+            addr_read(idx) != 0
+        });
+
+        if implemented != Ok(true) {
+            break;
+        }
+        count += 1;
+    }
+
+    if count == 0 {
+        return (0, 0);
+    }
+
+    // With A = OFF, bits below G read as zero
+    let granularity = 1 << (addr_read(0).trailing_zeros() + 2);
+    unsafe { GRANULARITY = granularity };
+    (count, granularity)
+}
+
+fn is_napot(base: usize, size: usize, granularity: usize) -> bool {
+    size.is_power_of_two() && size >= core::cmp::max(8, granularity) && base & (size - 1) == 0
+}
+
+fn region_cfg(region: &Region, mml: bool) -> u8 {
+    match (region.owner, mml) {
+        // L = 0 only restricts S/U-mode
+        (Owner::M, false) => 0,
+        (Owner::M, true) => L | region.perm,
+        (Owner::S, _) => region.perm,
+        (Owner::Shared, false) => R | W,
+        // L = 0, RWX = 011: reserved without MML, read-write for both M and S/U-mode with it
+        (Owner::Shared, true) => W | X,
+    }
+}
+
+// (pmpaddr, pmpcfg) pairs of the current layout, returns the number of entries used
+fn layout(entries: &mut [(usize, u8); MAX_ENTRIES], count: usize, granularity: usize, mml: bool) -> usize {
+    let mut used = if mml { RESV_ENTRIES } else { 0 };
+
+    // Keep the last entry for the catch-all region
+    for region in regions() {
+        if region.base & (granularity - 1) != 0 || region.size & (granularity - 1) != 0 {
+            crate::mprintln!("PMP region {} is not aligned to granularity 0x{:X}, ignored", region.name, granularity).unwrap();
+            continue;
+        }
+
+        if is_napot(region.base, region.size, granularity) {
+            if used + 1 >= count {
+                crate::mprintln!("Out of PMP entries, {} ignored", region.name).unwrap();
+                continue;
+            }
+
+            entries[used] = ((region.base | ((region.size >> 1) - 1)) >> 2, A_NAPOT | region_cfg(region, mml));
+            used += 1;
+        } else {
+            // TOR takes the previous entry as the lower bound
+            if used + 2 >= count {
+                crate::mprintln!("Out of PMP entries, {} ignored", region.name).unwrap();
+                continue;
+            }
+
+            entries[used] = (region.base >> 2, A_OFF);
+            entries[used + 1] = ((region.base + region.size) >> 2, A_TOR | region_cfg(region, mml));
+            used += 2;
+        }
+    }
+
+    // Everything else, the whole address space with NAPOT
+    entries[used] = (core::usize::MAX, A_NAPOT | R | W | X);
+    used + 1
+}
+
+fn program(entries: &[(usize, u8)]) {
+    let mut cfg = [0usize; MAX_ENTRIES / 8];
+    for (idx, (addr, entry_cfg)) in entries.iter().enumerate() {
+        addr_write(idx, *addr);
+        cfg[idx / 8] |= (*entry_cfg as usize) << ((idx % 8) * 8);
+    }
+
+    // Only registers holding implemented entries are written
+    for (i, val) in cfg[..(entries.len() + 7) / 8].iter().enumerate() {
+        cfg_write(i * 2, *val);
+    }
+}
+
+fn perm_str(region: &Region) -> [u8; 4] {
+    [
+        match region.owner {
+            Owner::M => b'M',
+            Owner::S => b'S',
+            Owner::Shared => b'*',
+        },
+        if region.perm & R != 0 { b'r' } else { b'-' },
+        if region.perm & W != 0 { b'w' } else { b'-' },
+        if region.perm & X != 0 { b'x' } else { b'-' },
+    ]
+}
+
+// Programs the PMP of the current hart, the layout is printed on the boot hart
+pub fn setup(verbose: bool) {
+    let (count, granularity) = probe();
+    if count == 0 {
+        crate::mprintln!("No PMP entries implemented").unwrap();
+        return;
+    }
+
+    let mml = smepmp();
+    if mml {
+        // RLB is kept, so that the reserved entries can still be edited once locked
+        mseccfg_set(MSECCFG_RLB);
+    }
+
+    /*
+     * The order matters: once MML is set, M-mode may only execute from locked entries.
+     * Setting it before the firmware text entry is programmed would fault on the very next
+     * fetch, and again on the fetch at mtvec. MMWP comes last, as it denies M-mode accesses
+     * not matching any entry.
+     */
+    let mut entries = [(0, 0); MAX_ENTRIES];
+    let used = layout(&mut entries, count, granularity, mml);
+    program(&entries[..used]);
+
+    if mml {
+        mseccfg_set(MSECCFG_MML);
+        mseccfg_set(MSECCFG_MMWP);
+    }
+
+    if verbose {
+        crate::mprintln!(
+            "PMP: {} entries, granularity 0x{:X}, Smepmp {}",
+            count,
+            granularity,
+            if mml { "enabled" } else { "disabled" }
+        )
+        .unwrap();
+        for region in regions() {
+            crate::mprintln!(
+                "  {:<12} 0x{:016X} - 0x{:016X} {}",
+                region.name,
+                region.base,
+                region.base + region.size,
+                core::str::from_utf8(&perm_str(region)).unwrap()
+            )
+            .unwrap();
+        }
+        for (idx, (addr, cfg)) in entries[..used].iter().enumerate() {
+            crate::mprintln!("  pmpaddr{:<2} 0x{:016X} cfg 0x{:02X}", idx, addr, cfg).unwrap();
+        }
+    }
+}
+
+/**
+ * Runs f with M-mode access to an S-mode buffer, given by physical address
+ *
+ * Without Smepmp, M-mode can access it anyway. Otherwise, the reserved entries are temporarily
+ * set to an M-only read-write TOR region. The caller checks that the buffer is outside of the firmware.
+ */
+pub fn with_s_memory<T, F: FnOnce() -> T>(base: usize, size: usize, f: F) -> T {
+    if !smepmp() {
+        return f();
+    }
+
+    let granularity = unsafe { GRANULARITY };
+    let start = base & !(granularity - 1);
+    let end = (base + size + granularity - 1) & !(granularity - 1);
+    let cfg = ((L | R | W | A_TOR) as usize) << 8;
+
+    addr_write(0, start >> 2);
+    addr_write(1, end >> 2);
+    unsafe { llvm_asm!("csrs pmpcfg0, $0" :: "r"(cfg) :: "volatile") };
+
+    let ret = f();
+
+    unsafe { llvm_asm!("csrc pmpcfg0, $0" :: "r"(0xFFusize << 8) :: "volatile") };
+    ret
+}
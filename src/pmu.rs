@@ -0,0 +1,521 @@
+use crate::sbi::{SBIErr, SBIRet};
+use core::convert::TryInto;
+
+/**
+ * SBI PMU extension
+ *
+ * Counter index 0 - (HW_CNT-1) maps to cycle, time, instret and hpmcounter3 - 31,
+ * firmware counters follow right after the hardware ones.
+ * Each firmware counter is bound to a single firmware event.
+ */
+
+#[repr(usize)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FWEvent {
+    MisalignedLoad,
+    MisalignedStore,
+    IllegalInsn,
+    SetTimer,
+    IPISent,
+    IPIRecvd,
+    FenceISent,
+    FenceIRecvd,
+    SFenceVMASent,
+    SFenceVMARecvd,
+    HFenceGVMASent,
+    HFenceGVMARecvd,
+    HFenceVVMASent,
+    HFenceVVMARecvd,
+    SBICall,
+}
+
+const FW_CNT: usize = 15;
+
+// Event code of each firmware counter, 65535 is the platform specific one, counting SBI calls
+const FW_CODES: [usize; FW_CNT] = [0, 1, 4, 5, 6, 7, 8, 9, 10, 11, 14, 15, 18, 19, 65535];
+
+const EVENT_TYPE_HW: usize = 0;
+const EVENT_TYPE_RAW: usize = 2;
+const EVENT_TYPE_FW: usize = 15;
+
+const EVENT_HW_CPU_CYCLES: usize = 1;
+const EVENT_HW_INSTRUCTIONS: usize = 2;
+
+mod flags {
+    pub const CFG_SKIP_MATCH: usize = 1 << 0;
+    pub const CFG_CLEAR_VALUE: usize = 1 << 1;
+    pub const CFG_AUTO_START: usize = 1 << 2;
+
+    pub const START_SET_INIT_VALUE: usize = 1 << 0;
+
+    pub const STOP_RESET: usize = 1 << 0;
+}
+
+const MAP_CAP: usize = 16;
+
+#[derive(Clone, Copy)]
+struct EventMap {
+    start: usize,
+    end: usize,
+    counters: u32,
+}
+
+#[derive(Clone, Copy)]
+struct EventSel {
+    event: usize,
+    selector: u64,
+}
+
+#[derive(Clone, Copy)]
+struct RawMap {
+    select: u64,
+    mask: u64,
+    counters: u32,
+}
+
+/**
+ * Platform counter layout, parsed from the riscv,pmu node on cold boot
+ */
+struct PMUConfig {
+    present: bool,
+    hw_cnt: usize,
+
+    maps: [Option<EventMap>; MAP_CAP],
+    sels: [Option<EventSel>; MAP_CAP],
+    raws: [Option<RawMap>; MAP_CAP],
+}
+
+static mut CONFIG: PMUConfig = PMUConfig {
+    present: false,
+    hw_cnt: 3,
+
+    maps: [None; MAP_CAP],
+    sels: [None; MAP_CAP],
+    raws: [None; MAP_CAP],
+};
+
+fn config() -> &'static PMUConfig {
+    unsafe { &CONFIG }
+}
+
+pub struct HartPMU {
+    hw_used: u32,
+    hw_started: u32,
+    fw_used: u32,
+    fw_started: u32,
+    fw_value: [u64; FW_CNT],
+}
+
+impl HartPMU {
+    pub const fn new() -> Self {
+        HartPMU {
+            hw_used: 0,
+            hw_started: 0,
+            fw_used: 0,
+            fw_started: 0,
+            fw_value: [0; FW_CNT],
+        }
+    }
+
+    // Firmware counters are numbered after hw_cnt hardware ones
+    fn used(&self, idx: usize, hw_cnt: usize) -> bool {
+        if idx >= hw_cnt {
+            self.fw_used & (1 << (idx - hw_cnt)) != 0
+        } else {
+            self.hw_used & (1 << idx) != 0
+        }
+    }
+
+    fn started(&self, idx: usize, hw_cnt: usize) -> bool {
+        if idx >= hw_cnt {
+            self.fw_started & (1 << (idx - hw_cnt)) != 0
+        } else {
+            self.hw_started & (1 << idx) != 0
+        }
+    }
+}
+
+macro_rules! hpm_csrs {
+    ($($idx:literal),*) => {
+        fn counter_write(idx: usize, val: usize) {
+            match idx {
+                0 => unsafe { llvm_asm!("csrw mcycle, $0" :: "r"(val) :: "volatile") },
+                2 => unsafe { llvm_asm!("csrw minstret, $0" :: "r"(val) :: "volatile") },
+                $($idx => unsafe {
+                    llvm_asm!(concat!("csrw mhpmcounter", stringify!($idx), ", $0") :: "r"(val) :: "volatile")
+                },)*
+                _ => {}
+            }
+        }
+
+        fn event_write(idx: usize, val: usize) {
+            match idx {
+                $($idx => unsafe {
+                    llvm_asm!(concat!("csrw mhpmevent", stringify!($idx), ", $0") :: "r"(val) :: "volatile")
+                },)*
+                _ => {}
+            }
+        }
+    };
+}
+
+hpm_csrs!(
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17,
+    18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+);
+
+fn inhibit_set(mask: u32) {
+    // mcountinhibit
+    unsafe { llvm_asm!("csrs 0x320, $0" :: "r"(mask as usize) :: "volatile") };
+}
+
+fn inhibit_clear(mask: u32) {
+    unsafe { llvm_asm!("csrc 0x320, $0" :: "r"(mask as usize) :: "volatile") };
+}
+
+fn cells(raw: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    raw.chunks_exact(4)
+        .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+}
+
+fn join(hi: u32, lo: u32) -> u64 {
+    ((hi as u64) << 32) | lo as u64
+}
+
+pub fn init(fdt: &fdt::FDT) {
+    let conf = unsafe { &mut CONFIG };
+    let mut counters = 0u32;
+
+    for node in fdt.nodes() {
+        if !node.is_compatible_with("riscv,pmu") {
+            continue;
+        }
+
+        conf.present = true;
+
+        if let Some(prop) = node.property("riscv,event-to-mhpmcounters") {
+            let mut iter = cells(prop.raw());
+            for slot in conf.maps.iter_mut() {
+                match (iter.next(), iter.next(), iter.next()) {
+                    (Some(start), Some(end), Some(bitmap)) => {
+                        counters |= bitmap;
+                        *slot = Some(EventMap { start: start as _, end: end as _, counters: bitmap });
+                    }
+                    _ => break,
+                }
+            }
+        }
+
+        if let Some(prop) = node.property("riscv,event-to-mhpmevent") {
+            let mut iter = cells(prop.raw());
+            for slot in conf.sels.iter_mut() {
+                match (iter.next(), iter.next(), iter.next()) {
+                    (Some(event), Some(hi), Some(lo)) => {
+                        *slot = Some(EventSel { event: event as _, selector: join(hi, lo) });
+                    }
+                    _ => break,
+                }
+            }
+        }
+
+        if let Some(prop) = node.property("riscv,raw-event-to-mhpmcounters") {
+            let mut iter = cells(prop.raw());
+            for slot in conf.raws.iter_mut() {
+                match (iter.next(), iter.next(), iter.next(), iter.next(), iter.next()) {
+                    (Some(sh), Some(sl), Some(mh), Some(ml), Some(bitmap)) => {
+                        counters |= bitmap;
+                        *slot = Some(RawMap { select: join(sh, sl), mask: join(mh, ml), counters: bitmap });
+                    }
+                    _ => break,
+                }
+            }
+        }
+    }
+
+    // cycle, time and instret are always there
+    conf.hw_cnt = core::cmp::max(3, 32 - counters.leading_zeros() as usize);
+    crate::mprintln!("PMU: {} hardware counters, {} firmware counters", conf.hw_cnt, FW_CNT).unwrap();
+}
+
+// Per-hart setup, stops all programmable counters
+pub fn hart_init() {
+    if config().present {
+        inhibit_set(hw_mask() & !0b111);
+    }
+}
+
+fn hw_mask() -> u32 {
+    (((1u64 << config().hw_cnt) - 1) as u32) & !0b10 // time cannot be configured
+}
+
+fn total() -> usize {
+    config().hw_cnt + FW_CNT
+}
+
+pub fn fw_count(ev: FWEvent) {
+    let pmu = &mut crate::mem::local_data().pmu;
+    let slot = ev as usize;
+    if pmu.fw_started & (1 << slot) != 0 {
+        pmu.fw_value[slot] += 1;
+    }
+}
+
+// Collects counters selected by base & mask, rejecting indexes out of range
+fn selected(base: usize, mask: usize) -> Option<u64> {
+    let mut result = 0u64;
+    let mut remaining = mask;
+    while remaining != 0 {
+        let bit = remaining.trailing_zeros() as usize;
+        let idx = base.checked_add(bit)?;
+        if idx >= total() {
+            return None;
+        }
+        result |= 1 << idx;
+        remaining ^= 1 << bit;
+    }
+    Some(result)
+}
+
+/**
+ * Finds hardware counters capable of counting an event, with the mhpmevent value to use
+ *
+ * Programmable counters are only candidates if a selector is known for the event.
+ */
+fn hw_candidates(event_idx: usize, event_data: usize) -> (u32, usize) {
+    let ty = (event_idx >> 16) & 0xF;
+    let code = event_idx & 0xFFFF;
+    let conf = config();
+
+    let mut cands = 0u32;
+    let mut selector = None;
+
+    if ty == EVENT_TYPE_HW && code == EVENT_HW_CPU_CYCLES {
+        cands |= 1 << 0;
+    } else if ty == EVENT_TYPE_HW && code == EVENT_HW_INSTRUCTIONS {
+        cands |= 1 << 2;
+    }
+
+    if ty == EVENT_TYPE_RAW {
+        for raw in conf.raws.iter().filter_map(|r| *r) {
+            if (event_data as u64) & raw.mask == raw.select {
+                cands |= raw.counters;
+            }
+        }
+        selector = Some(event_data);
+    } else {
+        for map in conf.maps.iter().filter_map(|m| *m) {
+            if event_idx >= map.start && event_idx <= map.end {
+                cands |= map.counters;
+            }
+        }
+
+        if let Some(sel) = conf.sels.iter().filter_map(|s| *s).find(|s| s.event == event_idx) {
+            selector = Some(sel.selector as usize);
+        }
+    }
+
+    // Without a selector, only cycle and instret can count the event
+    let cands = match selector {
+        Some(_) => cands,
+        None => cands & ((1 << 0) | (1 << 2)),
+    };
+
+    (cands & hw_mask(), selector.unwrap_or(0))
+}
+
+pub fn num_counters() -> SBIRet {
+    total().into()
+}
+
+pub fn counter_get_info(idx: usize) -> SBIRet {
+    if idx < config().hw_cnt {
+        // CSR number and width - 1
+        ((0xC00 + idx) | (63 << 12)).into()
+    } else if idx < total() {
+        (1usize << (core::mem::size_of::<usize>() * 8 - 1)).into()
+    } else {
+        SBIErr::InvalidParam.into()
+    }
+}
+
+pub fn counter_config_matching(
+    base: usize,
+    mask: usize,
+    config_flags: usize,
+    event_idx: usize,
+    event_data: usize,
+) -> SBIRet {
+    let pmu = &mut crate::mem::local_data().pmu;
+    let hw_cnt = config().hw_cnt;
+
+    let sel = match selected(base, mask) {
+        Some(s) if s != 0 => s,
+        _ => return SBIErr::InvalidParam.into(),
+    };
+
+    let idx = if config_flags & flags::CFG_SKIP_MATCH != 0 {
+        // Reuses the counter configured before
+        let idx = sel.trailing_zeros() as usize;
+        if !pmu.used(idx, hw_cnt) {
+            return SBIErr::InvalidParam.into();
+        }
+        idx
+    } else if (event_idx >> 16) & 0xF == EVENT_TYPE_FW {
+        let code = event_idx & 0xFFFF;
+        let slot = match FW_CODES.iter().position(|c| *c == code) {
+            Some(s) => s,
+            None => return SBIErr::NotSupported.into(),
+        };
+
+        let idx = hw_cnt + slot;
+        if sel & (1 << idx) == 0 || pmu.fw_used & (1 << slot) != 0 {
+            return SBIErr::NotSupported.into();
+        }
+
+        pmu.fw_used |= 1 << slot;
+        idx
+    } else {
+        let (cands, selector) = hw_candidates(event_idx, event_data);
+        let free = cands & (sel as u32) & !pmu.hw_used;
+        if free == 0 {
+            return SBIErr::NotSupported.into();
+        }
+
+        let idx = free.trailing_zeros() as usize;
+        if config().present {
+            inhibit_set(1 << idx);
+        }
+        if idx >= 3 {
+            event_write(idx, selector);
+        }
+
+        pmu.hw_used |= 1 << idx;
+        pmu.hw_started &= !(1 << idx);
+        idx
+    };
+
+    if config_flags & flags::CFG_CLEAR_VALUE != 0 {
+        if idx >= hw_cnt {
+            pmu.fw_value[idx - hw_cnt] = 0;
+        } else {
+            counter_write(idx, 0);
+        }
+    }
+
+    if config_flags & flags::CFG_AUTO_START != 0 {
+        if idx >= hw_cnt {
+            pmu.fw_started |= 1 << (idx - hw_cnt);
+        } else {
+            if config().present {
+                inhibit_clear(1 << idx);
+            }
+            pmu.hw_started |= 1 << idx;
+        }
+    }
+
+    idx.into()
+}
+
+pub fn counter_start(base: usize, mask: usize, start_flags: usize, initial: usize) -> SBIRet {
+    let pmu = &mut crate::mem::local_data().pmu;
+    let hw_cnt = config().hw_cnt;
+
+    let mut sel = match selected(base, mask) {
+        Some(s) => s,
+        None => return SBIErr::InvalidParam.into(),
+    };
+
+    // Checked before starting anything
+    let idxs = || (0..total()).filter(move |idx| sel & (1 << idx) != 0);
+    if !idxs().all(|idx| pmu.used(idx, hw_cnt)) {
+        return SBIErr::InvalidParam.into();
+    }
+    if idxs().any(|idx| pmu.started(idx, hw_cnt)) {
+        return SBIErr::AlreadyStarted.into();
+    }
+
+    while sel != 0 {
+        let idx = sel.trailing_zeros() as usize;
+        sel ^= 1 << idx;
+
+        if idx >= hw_cnt {
+            let slot = idx - hw_cnt;
+            if start_flags & flags::START_SET_INIT_VALUE != 0 {
+                pmu.fw_value[slot] = initial as u64;
+            }
+            pmu.fw_started |= 1 << slot;
+        } else {
+            if start_flags & flags::START_SET_INIT_VALUE != 0 {
+                counter_write(idx, initial);
+            }
+            if config().present {
+                inhibit_clear(1 << idx);
+            }
+            pmu.hw_started |= 1 << idx;
+        }
+    }
+
+    0usize.into()
+}
+
+pub fn counter_stop(base: usize, mask: usize, stop_flags: usize) -> SBIRet {
+    let pmu = &mut crate::mem::local_data().pmu;
+    let hw_cnt = config().hw_cnt;
+
+    let mut sel = match selected(base, mask) {
+        Some(s) => s,
+        None => return SBIErr::InvalidParam.into(),
+    };
+
+    // Checked before stopping anything
+    let idxs = || (0..total()).filter(move |idx| sel & (1 << idx) != 0);
+    if !idxs().all(|idx| pmu.used(idx, hw_cnt)) {
+        return SBIErr::InvalidParam.into();
+    }
+    if !idxs().all(|idx| pmu.started(idx, hw_cnt)) {
+        return SBIErr::AlreadyStopped.into();
+    }
+
+    while sel != 0 {
+        let idx = sel.trailing_zeros() as usize;
+        sel ^= 1 << idx;
+
+        if idx >= hw_cnt {
+            let slot = idx - hw_cnt;
+            pmu.fw_started &= !(1 << slot);
+            if stop_flags & flags::STOP_RESET != 0 {
+                pmu.fw_used &= !(1 << slot);
+            }
+        } else {
+            // cycle and instret keep running without mcountinhibit
+            if config().present {
+                inhibit_set(1 << idx);
+            }
+            pmu.hw_started &= !(1 << idx);
+            if stop_flags & flags::STOP_RESET != 0 {
+                pmu.hw_used &= !(1 << idx);
+            }
+        }
+    }
+
+    0usize.into()
+}
+
+pub fn counter_fw_read(idx: usize) -> SBIRet {
+    let hw_cnt = config().hw_cnt;
+    if idx < hw_cnt || idx >= total() {
+        return SBIErr::InvalidParam.into();
+    }
+
+    (crate::mem::local_data().pmu.fw_value[idx - hw_cnt] as usize).into()
+}
+
+// Upper 32 bits of a firmware counter, only meaningful on RV32, added in SBI 2.0
+pub fn counter_fw_read_hi(idx: usize) -> SBIRet {
+    let hw_cnt = config().hw_cnt;
+    if idx < hw_cnt || idx >= total() {
+        return SBIErr::InvalidParam.into();
+    }
+
+    0usize.into()
+}
@@ -0,0 +1,76 @@
+use crate::fdt_mut::{FDTError, FDTMut};
+
+/**
+ * /reserved-memory fixups
+ *
+ * M-mode memory is described by no-map children of /reserved-memory, so that
+ * the S-mode kernel never maps or allocates it.
+ */
+
+// "<prefix>@<hex base>", formatted into buf
+fn unit_name<'a>(buf: &'a mut [u8; 32], prefix: &str, base: usize) -> &'a str {
+    let mut len = prefix.len();
+    buf[..len].copy_from_slice(prefix.as_bytes());
+    buf[len] = b'@';
+    len += 1;
+
+    let digits = core::cmp::max(1, (64 - base.leading_zeros() as usize + 3) / 4);
+    for i in (0..digits).rev() {
+        let digit = (base >> (i * 4)) & 0xF;
+        buf[len] = b"0123456789abcdef"[digit];
+        len += 1;
+    }
+
+    core::str::from_utf8(&buf[..len]).unwrap()
+}
+
+fn cells(fdt: &FDTMut, node: usize, name: &str) -> usize {
+    fdt.property(node, name)
+        .filter(|p| p.len() == 4)
+        .map(|p| u32::from_be_bytes([p[0], p[1], p[2], p[3]]) as usize)
+        .unwrap_or(2)
+}
+
+// Encodes val in the given number of cells, returns the number of bytes written
+fn encode(buf: &mut [u8], cells: usize, val: usize) -> usize {
+    match cells {
+        1 => buf[..4].copy_from_slice(&(val as u32).to_be_bytes()),
+        _ => buf[..8].copy_from_slice(&(val as u64).to_be_bytes()),
+    }
+    cells * 4
+}
+
+fn reserved_memory(fdt: &mut FDTMut) -> Result<usize, FDTError> {
+    let root = fdt.root();
+    if let Some(node) = fdt.subnode(root, "reserved-memory") {
+        return Ok(node);
+    }
+
+    let node = fdt.add_subnode(root, "reserved-memory")?;
+    fdt.set_property_u32(node, "#address-cells", 2)?;
+    fdt.set_property_u32(node, "#size-cells", 2)?;
+    fdt.set_property(node, "ranges", &[])?;
+    Ok(node)
+}
+
+pub fn add_no_map(fdt: &mut FDTMut, base: usize, size: usize) -> Result<(), FDTError> {
+    let parent = reserved_memory(fdt)?;
+    let addr_cells = cells(fdt, parent, "#address-cells");
+    let size_cells = cells(fdt, parent, "#size-cells");
+
+    if (addr_cells == 1 && base >> 32 != 0) || (size_cells == 1 && size >> 32 != 0) {
+        return Err(FDTError::BadLayout);
+    }
+
+    let mut name = [0; 32];
+    let node = fdt.add_subnode(parent, unit_name(&mut name, "mmode_resv", base))?;
+
+    let mut reg = [0; 16];
+    let len = encode(&mut reg, addr_cells, base);
+    let len = len + encode(&mut reg[len..], size_cells, size);
+    fdt.set_property(node, "reg", &reg[..len])?;
+    fdt.set_property(node, "no-map", &[])?;
+
+    crate::mprintln!("Reserved 0x{:016X}, size 0x{:X}", base, size).unwrap();
+    Ok(())
+}
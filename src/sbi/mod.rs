@@ -1,8 +1,8 @@
 const SBI_IMPL_ID: usize = 0x776f654d; // Meow in little endian
 const SBI_IMPL_VERSION: usize = 0x1;
 
-const SBI_SPEC_MAJOR: usize = 0;
-const SBI_SPEC_MINOR: usize = 2;
+const SBI_SPEC_MAJOR: usize = 2;
+const SBI_SPEC_MINOR: usize = 0;
 
 #[allow(dead_code)]
 #[repr(usize)]
@@ -24,10 +24,13 @@ pub enum SBIExt {
     IPI = 0x735049,
     RFENCE = 0x52464E43,
     TIME = 0x54494D45,
-    // Sorry, no HSM
+    HSM = 0x48534D,
+    SRST = 0x53525354,
+    DBCN = 0x4442434E,
+    PMU = 0x504D55,
 }
 
-const ALL_SBI_EXT: [SBIExt; 13] = [
+const ALL_SBI_EXT: [SBIExt; 17] = [
     SBIExt::SetTimer,
     SBIExt::ConsolePutChar,
     SBIExt::ConsoleGetChar,
@@ -41,6 +44,10 @@ const ALL_SBI_EXT: [SBIExt; 13] = [
     SBIExt::IPI,
     SBIExt::RFENCE,
     SBIExt::TIME,
+    SBIExt::HSM,
+    SBIExt::SRST,
+    SBIExt::DBCN,
+    SBIExt::PMU,
 ];
 
 #[repr(usize)]
@@ -68,6 +75,8 @@ pub enum SBIErr {
     Denied = -4,
     InvalidAddress = -5,
     AlreadyAvailable = -6,
+    AlreadyStarted = -7,
+    AlreadyStopped = -8,
 }
 
 pub struct SBIRet {
@@ -99,8 +108,15 @@ impl From<Option<usize>> for SBIRet {
     }
 }
 
-pub fn call(ext: usize, func: usize, a0: usize, a1: usize, a2: usize) -> SBIRet {
-    // FIXME: hart_mask_base
+pub fn call(
+    ext: usize,
+    func: usize,
+    a0: usize,
+    a1: usize,
+    a2: usize,
+    a3: usize,
+    a4: usize,
+) -> SBIRet {
     let ext = unsafe { core::mem::transmute(ext) };
     // crate::mprintln!("[MeowSBI] SBI Call: {:?}", ext).unwrap();
     match ext {
@@ -116,12 +132,13 @@ pub fn call(ext: usize, func: usize, a0: usize, a1: usize, a2: usize) -> SBIRet
                     SBIBaseFunc::GetSBIImplID => SBI_IMPL_ID.into(),
                     SBIBaseFunc::GetSBIImplVersion => SBI_IMPL_VERSION.into(),
                     SBIBaseFunc::ProbExtension => {
+                        // Present extensions report a non-zero value
                         for ext in &ALL_SBI_EXT {
                             if *ext as usize == a0 {
-                                return SBIErr::Success.into();
+                                return 1usize.into();
                             }
                         }
-                        return SBIErr::NotSupported.into();
+                        return 0usize.into();
                     }
                     SBIBaseFunc::GetMVENDROID => {
                         riscv::register::mvendorid::read().map(|e| e.bits()).into()
@@ -150,15 +167,16 @@ pub fn call(ext: usize, func: usize, a0: usize, a1: usize, a2: usize) -> SBIRet
         },
         SBIExt::SendIPI => ipi_ptr(a0, crate::ipi::IPIReq::S_IPI),
         SBIExt::RemoteFENCE_I => ipi_ptr(a0, crate::ipi::IPIReq::FENCE_I),
-        SBIExt::RemoteSFENCE_VMA => ipi_ptr(a0, crate::ipi::IPIReq::SFENCE_VMA),
-        SBIExt::RemoteSFENCE_VMA_ASID => ipi(
-            unsafe { *(a0 as *const usize) },
-            0,
-            crate::ipi::IPIReq::SFENCE_VMA,
-        ),
-        SBIExt::Shutdown => loop {
-            core::sync::atomic::spin_loop_hint()
-        }, // TOOD: proper impl
+        SBIExt::RemoteSFENCE_VMA => ipi_ptr(a0, sfence_vma(a1, a2, None)),
+        SBIExt::RemoteSFENCE_VMA_ASID => ipi_ptr(a0, sfence_vma(a1, a2, Some(a3))),
+        SBIExt::Shutdown => {
+            use crate::platform::{ResetReason, ResetType};
+            system_reset(ResetType::Shutdown, ResetReason::NoReason);
+            crate::mprintln!("Platform cannot power off, halting").unwrap();
+            loop {
+                unsafe { riscv::asm::wfi() };
+            }
+        }
 
         SBIExt::IPI => {
             if func != 0 {
@@ -169,7 +187,13 @@ pub fn call(ext: usize, func: usize, a0: usize, a1: usize, a2: usize) -> SBIRet
         }
         SBIExt::RFENCE => match func {
             0 => ipi(a0, a1, crate::ipi::IPIReq::FENCE_I),
-            1 | 2 => ipi(a0, a1, crate::ipi::IPIReq::SFENCE_VMA),
+            1 => ipi(a0, a1, sfence_vma(a2, a3, None)),
+            2 => ipi(a0, a1, sfence_vma(a2, a3, Some(a4))),
+            3..=6 if !crate::ipi::has_h() => SBIErr::NotSupported.into(),
+            3 => ipi(a0, a1, hfence_gvma(a2, a3, Some(a4))),
+            4 => ipi(a0, a1, hfence_gvma(a2, a3, None)),
+            5 => ipi(a0, a1, hfence_vvma(a2, a3, Some(a4))),
+            6 => ipi(a0, a1, hfence_vvma(a2, a3, None)),
             _ => SBIErr::NotSupported.into(),
         },
         SBIExt::TIME => {
@@ -179,56 +203,184 @@ pub fn call(ext: usize, func: usize, a0: usize, a1: usize, a2: usize) -> SBIRet
                 set_timer(a0)
             }
         }
+        SBIExt::HSM => match func {
+            0 => crate::hsm::hart_start(a0, a1, a2),
+            1 => crate::hsm::hart_stop(),
+            2 => crate::hsm::hart_get_status(a0),
+            3 => crate::hsm::hart_suspend(a0, a1, a2),
+            _ => SBIErr::NotSupported.into(),
+        },
+        SBIExt::SRST => {
+            if func != 0 {
+                SBIErr::NotSupported.into()
+            } else {
+                srst(a0, a1)
+            }
+        }
+        SBIExt::DBCN => match func {
+            0 => console_write(a0, a1, a2),
+            1 => console_read(a0, a1, a2),
+            2 => {
+                crate::serial::putc(a0 as u8);
+                0usize.into()
+            }
+            _ => SBIErr::NotSupported.into(),
+        },
+        SBIExt::PMU => match func {
+            0 => crate::pmu::num_counters(),
+            1 => crate::pmu::counter_get_info(a0),
+            2 => crate::pmu::counter_config_matching(a0, a1, a2, a3, a4),
+            3 => crate::pmu::counter_start(a0, a1, a2, a3),
+            4 => crate::pmu::counter_stop(a0, a1, a2),
+            5 => crate::pmu::counter_fw_read(a0),
+            6 => crate::pmu::counter_fw_read_hi(a0),
+            // 7 is snapshot_set_shmem, which is optional
+            _ => SBIErr::NotSupported.into(),
+        },
+    }
+}
+
+// Checks the physical buffer given by S-mode, perm is the access we are going to make
+fn console_buffer(num_bytes: usize, base_lo: usize, base_hi: usize, perm: u8) -> Option<&'static mut [u8]> {
+    if base_hi != 0 || !crate::mem::s_accessible(base_lo, num_bytes, perm) {
+        return None;
+    }
+
+    Some(unsafe { core::slice::from_raw_parts_mut(base_lo as *mut u8, num_bytes) })
+}
+
+fn console_write(num_bytes: usize, base_lo: usize, base_hi: usize) -> SBIRet {
+    match console_buffer(num_bytes, base_lo, base_hi, crate::pmp::R) {
+        Some(buf) => {
+            crate::pmp::with_s_memory(base_lo, num_bytes, || crate::serial::write(buf));
+            buf.len().into()
+        }
+        None => SBIErr::InvalidParam.into(),
+    }
+}
+
+fn console_read(num_bytes: usize, base_lo: usize, base_hi: usize) -> SBIRet {
+    match console_buffer(num_bytes, base_lo, base_hi, crate::pmp::W) {
+        Some(buf) => {
+            let cnt = crate::pmp::with_s_memory(base_lo, num_bytes, || {
+                let mut cnt = 0;
+                while cnt < buf.len() {
+                    match crate::serial::try_getc() {
+                        Some(c) => buf[cnt] = c,
+                        None => break,
+                    }
+                    cnt += 1;
+                }
+                cnt
+            });
+            cnt.into()
+        }
+        None => SBIErr::InvalidParam.into(),
+    }
+}
+
+fn srst(reset_type: usize, reason: usize) -> SBIRet {
+    use crate::platform::{ResetReason, ResetType};
+    let reset_type = match reset_type {
+        0 => ResetType::Shutdown,
+        1 => ResetType::ColdReboot,
+        2 => ResetType::WarmReboot,
+        _ => return SBIErr::InvalidParam.into(),
+    };
+
+    let reason = match reason {
+        0 => ResetReason::NoReason,
+        1 => ResetReason::SystemFailure,
+        _ => return SBIErr::InvalidParam.into(),
+    };
+
+    system_reset(reset_type, reason);
+    SBIErr::NotSupported.into()
+}
+
+// Only returns if the platform cannot perform this reset
+fn system_reset(reset_type: crate::platform::ResetType, reason: crate::platform::ResetReason) {
+    use crate::platform::PlatformOps;
+    crate::mprintln!("System reset: {:?}, reason: {:?}", reset_type, reason).unwrap();
+
+    if crate::mem::local_data().platform().system_reset(reset_type, reason) {
+        // Wait for the reset to take effect
+        loop {
+            unsafe { riscv::asm::wfi() };
+        }
     }
 }
 
 fn set_timer(timer: usize) -> SBIRet {
     use crate::platform::PlatformOps;
-    crate::mem::local_data().platform().set_timer(timer as u64);
+    crate::pmu::fw_count(crate::pmu::FWEvent::SetTimer);
+
+    let data = crate::mem::local_data();
+    if data.sstc {
+        // STIP follows stimecmp in hardware
+        unsafe { llvm_asm!("csrw 0x14D, $0" :: "r"(timer) :: "volatile") };
+        return 0usize.into();
+    }
 
-    // TODO: mtip may show a false postive (when setting a larger mtimecmp), and stimer may never be cleared
-    let mtip = riscv::register::mip::read().mtimer();
-    if mtip {
+    // A new deadline always retracts the previous STIP
+    unsafe { riscv::register::mip::clear_stimer() };
+    data.platform().set_timer(timer as u64);
+
+    // MTIP may still reflect the old mtimecmp, so compare against mtime instead
+    if data.platform().read_time() >= timer as u64 {
         unsafe {
             riscv::register::mie::clear_mtimer();
             riscv::register::mip::set_stimer();
         }
     } else {
-        unsafe {
-            riscv::register::mie::set_mtimer();
-            riscv::register::mip::clear_stimer();
-        }
+        // If the deadline passes right now, the M-timer interrupt sets STIP after we return
+        unsafe { riscv::register::mie::set_mtimer() };
     }
     0usize.into()
 }
 
+fn sfence_vma(start: usize, size: usize, asid: Option<usize>) -> crate::ipi::IPIReq {
+    crate::ipi::IPIReq::SFENCE_VMA(crate::ipi::Fence::new(start, size, asid, None))
+}
+
+fn hfence_gvma(start: usize, size: usize, vmid: Option<usize>) -> crate::ipi::IPIReq {
+    crate::ipi::IPIReq::HFENCE_GVMA(crate::ipi::Fence::new(start, size, None, vmid))
+}
+
+// Targets fence the caller's current VM
+fn hfence_vvma(start: usize, size: usize, asid: Option<usize>) -> crate::ipi::IPIReq {
+    let vmid = crate::ipi::current_vmid();
+    crate::ipi::IPIReq::HFENCE_VVMA(crate::ipi::Fence::new(start, size, asid, Some(vmid)))
+}
+
 fn ipi(mask: usize, base: usize, ipi: crate::ipi::IPIReq) -> SBIRet {
-    // TODO: handles HART_COUNT > 64
-    let mask = if base == core::usize::MAX {
-        core::usize::MAX
-    } else {
-        mask << base
-    };
+    if base == core::usize::MAX {
+        // All harts in the system
+        crate::ipi::send_ipi(crate::mem::enabled_harts(), ipi);
+        return 0usize.into();
+    }
+
+    // Overflowing hartids map to usize::MAX, which is never enabled
+    let harts = (0..64)
+        .filter(move |i| mask & (1 << i) != 0)
+        .map(move |i| base.checked_add(i).unwrap_or(core::usize::MAX));
+
+    if !harts.clone().all(crate::mem::hart_enabled) {
+        return SBIErr::InvalidParam.into();
+    }
 
-    crate::ipi::send_ipi(mask, ipi);
+    crate::ipi::send_ipi(harts, ipi);
     0usize.into()
 }
 
+// Legacy extensions pass a pointer to an unsigned long hart mask, null meaning all harts
 fn ipi_ptr(p: usize, i: crate::ipi::IPIReq) -> SBIRet {
-    let mut mask = core::usize::MAX;
-    if p != 0 {
-        // MPRV = 1, then read
-        unsafe {
-            llvm_asm!(r#"
-            li t0, (1<<17)
-            mv t1, $1
-            csrrs t0, mstatus, t0
-            lw t1, 0(t1)
-            csrw mstatus, t0
-            mv $0, t1
-            "# : "=r"(mask) : "r"(p) :: "volatile");
-        }
+    if p == 0 {
+        return ipi(0, core::usize::MAX, i);
     }
 
-    ipi(mask, 0, i)
+    match crate::trap::mprv::load_usize(p) {
+        Ok(mask) => ipi(mask, 0, i),
+        Err(_) => SBIErr::InvalidAddress.into(),
+    }
 }
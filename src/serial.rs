@@ -35,6 +35,24 @@ pub fn getc() -> u8 {
     ret
 }
 
+pub fn try_getc() -> Option<u8> {
+    while locking::READ.compare_and_swap(false, true, Ordering::Acquire) {
+        spin_loop_hint();
+    }
+
+    let ret = crate::mem::local_data().platform().try_get_char();
+
+    locking::READ.store(false, Ordering::Release);
+
+    ret
+}
+
+pub fn write(buf: &[u8]) {
+    for c in buf {
+        putc(*c);
+    }
+}
+
 pub fn print(s: &str) {
     for c in s.as_bytes() {
         putc(*c);
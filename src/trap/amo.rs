@@ -0,0 +1,135 @@
+use super::mprv;
+use super::TrapFrame;
+
+/**
+ * AMO emulation for cores with only LR/SC
+ *
+ * Each AMO is replaced by a constrained LR/SC loop, executed with MPRV set so that
+ * the access goes through the trapping context's address space.
+ * t1 holds the new value computed from the old value ($0) and rs2 ($2).
+ *
+ * A faulting LR is skipped by catch_fault, leaving no reservation, so the SC would fail forever.
+ * Whenever the SC fails, mprv_faulted ($3) is checked with MPRV cleared, as it's M-mode memory,
+ * and the loop is left if a fault was recorded. A faulting SC is skipped with t2 = 0, which also
+ * ends the loop. The check sits outside of LR/SC, keeping the loop constrained.
+ */
+
+macro_rules! amo_fn {
+    ($name:ident, $lr:literal, $sc:literal, $op:literal) => {
+        fn $name(addr: usize, src: usize) -> usize {
+            let old: usize;
+            let faulted = &crate::mem::local_data().mprv_faulted as *const usize;
+            unsafe {
+                llvm_asm!(concat!(r#"
+                li t0, (1<<17)
+                csrrs t0, mstatus, t0
+                1: "#, $lr, r#" $0, ($1)
+                "#, $op, r#"
+                li t2, 0
+                "#, $sc, r#" t2, t1, ($1)
+                beqz t2, 3f
+                csrw mstatus, t0
+                ld t2, 0($3)
+                bnez t2, 3f
+                li t1, (1<<17)
+                csrs mstatus, t1
+                j 1b
+                3: csrw mstatus, t0
+                "#) : "=&r"(old) : "r"(addr), "r"(src), "r"(faulted) : "t0", "t1", "t2", "memory" : "volatile");
+            }
+            old
+        }
+    };
+}
+
+amo_fn!(swap_w, "lr.w.aqrl", "sc.w.aqrl", "mv t1, $2");
+amo_fn!(add_w, "lr.w.aqrl", "sc.w.aqrl", "add t1, $0, $2");
+amo_fn!(xor_w, "lr.w.aqrl", "sc.w.aqrl", "xor t1, $0, $2");
+amo_fn!(and_w, "lr.w.aqrl", "sc.w.aqrl", "and t1, $0, $2");
+amo_fn!(or_w, "lr.w.aqrl", "sc.w.aqrl", "or t1, $0, $2");
+amo_fn!(min_w, "lr.w.aqrl", "sc.w.aqrl", "mv t1, $0\n bge $2, $0, 2f\n mv t1, $2\n 2:");
+amo_fn!(max_w, "lr.w.aqrl", "sc.w.aqrl", "mv t1, $0\n bge $0, $2, 2f\n mv t1, $2\n 2:");
+amo_fn!(minu_w, "lr.w.aqrl", "sc.w.aqrl", "mv t1, $0\n bgeu $2, $0, 2f\n mv t1, $2\n 2:");
+amo_fn!(maxu_w, "lr.w.aqrl", "sc.w.aqrl", "mv t1, $0\n bgeu $0, $2, 2f\n mv t1, $2\n 2:");
+
+amo_fn!(swap_d, "lr.d.aqrl", "sc.d.aqrl", "mv t1, $2");
+amo_fn!(add_d, "lr.d.aqrl", "sc.d.aqrl", "add t1, $0, $2");
+amo_fn!(xor_d, "lr.d.aqrl", "sc.d.aqrl", "xor t1, $0, $2");
+amo_fn!(and_d, "lr.d.aqrl", "sc.d.aqrl", "and t1, $0, $2");
+amo_fn!(or_d, "lr.d.aqrl", "sc.d.aqrl", "or t1, $0, $2");
+amo_fn!(min_d, "lr.d.aqrl", "sc.d.aqrl", "mv t1, $0\n bge $2, $0, 2f\n mv t1, $2\n 2:");
+amo_fn!(max_d, "lr.d.aqrl", "sc.d.aqrl", "mv t1, $0\n bge $0, $2, 2f\n mv t1, $2\n 2:");
+amo_fn!(minu_d, "lr.d.aqrl", "sc.d.aqrl", "mv t1, $0\n bgeu $2, $0, 2f\n mv t1, $2\n 2:");
+amo_fn!(maxu_d, "lr.d.aqrl", "sc.d.aqrl", "mv t1, $0\n bgeu $0, $2, 2f\n mv t1, $2\n 2:");
+
+// AMOs report all faults as store / AMO faults
+fn as_store_fault(cause: usize) -> usize {
+    match cause {
+        4 => 6,   // Misaligned
+        5 => 7,   // Access fault
+        13 => 15, // Page fault
+        c => c,
+    }
+}
+
+// Returns false if the instruction is not an AMO
+pub fn emulate(tf: &mut TrapFrame, inst: u32) -> bool {
+    let inst = inst as usize;
+    if inst & 0x7F != 0x2F {
+        return false;
+    }
+
+    let rd = (inst >> 7) & 31;
+    let rs1 = (inst >> 15) & 31;
+    let rs2 = (inst >> 20) & 31;
+    let funct3 = (inst >> 12) & 7;
+    let funct5 = inst >> 27;
+
+    let op: fn(usize, usize) -> usize = match (funct3, funct5) {
+        (0b010, 0b00001) => swap_w,
+        (0b010, 0b00000) => add_w,
+        (0b010, 0b00100) => xor_w,
+        (0b010, 0b01100) => and_w,
+        (0b010, 0b01000) => or_w,
+        (0b010, 0b10000) => min_w,
+        (0b010, 0b10100) => max_w,
+        (0b010, 0b11000) => minu_w,
+        (0b010, 0b11100) => maxu_w,
+
+        (0b011, 0b00001) => swap_d,
+        (0b011, 0b00000) => add_d,
+        (0b011, 0b00100) => xor_d,
+        (0b011, 0b01100) => and_d,
+        (0b011, 0b01000) => or_d,
+        (0b011, 0b10000) => min_d,
+        (0b011, 0b10100) => max_d,
+        (0b011, 0b11000) => minu_d,
+        (0b011, 0b11100) => maxu_d,
+
+        _ => return false,
+    };
+
+    let addr = tf.get(rs1);
+    let mut src = tf.get(rs2);
+    if funct3 == 0b010 {
+        // W variants compare sign-extended values
+        src = src as i32 as isize as usize;
+    }
+
+    let width = 1 << funct3;
+    if addr & (width - 1) != 0 {
+        super::redirect(6, addr);
+        return true;
+    }
+
+    match mprv::guarded(|| op(addr, src)) {
+        Ok(old) => {
+            tf.set(rd, old);
+            let mepc = riscv::register::mepc::read();
+            riscv::register::mepc::write(mepc + 4);
+        }
+        Err((cause, tval)) => super::redirect(as_store_fault(cause), tval),
+    }
+
+    true
+}
@@ -0,0 +1,53 @@
+use super::mprv;
+use super::TrapFrame;
+
+const CSR_TIME: usize = 0xC01;
+const CSR_TIMEH: usize = 0xC81;
+
+// Emulates reads of time / timeh, i.e. csrr{s,c}[i] rd, time, x0
+fn emulate_rdtime(tf: &mut TrapFrame, inst: u32) -> bool {
+    let inst = inst as usize;
+    if inst & 0x7F != 0x73 {
+        return false;
+    }
+
+    let funct3 = (inst >> 12) & 7;
+    let rs1 = (inst >> 15) & 31;
+    let csr = inst >> 20;
+    if rs1 != 0 || !(funct3 == 0b010 || funct3 == 0b011 || funct3 == 0b110 || funct3 == 0b111) {
+        return false;
+    }
+
+    use crate::platform::PlatformOps;
+    let time = crate::mem::local_data().platform().read_time();
+    let val = match csr {
+        CSR_TIME => time as usize,
+        CSR_TIMEH => (time >> 32) as usize,
+        _ => return false,
+    };
+
+    tf.set((inst >> 7) & 31, val);
+    let mepc = riscv::register::mepc::read();
+    riscv::register::mepc::write(mepc + 4);
+    true
+}
+
+// Emulates instructions missing on the hart, and forwards everything else to S-mode
+pub fn handle(tf: &mut TrapFrame, mcause: usize, mtval: usize) {
+    // mtval may or may not hold the faulting instruction
+    let inst = if mtval != 0 {
+        mtval as u32
+    } else {
+        match mprv::fetch_inst(riscv::register::mepc::read()) {
+            Ok((inst, _)) => inst,
+            Err((cause, tval)) => return super::redirect(cause, tval),
+        }
+    };
+
+    if emulate_rdtime(tf, inst) || super::amo::emulate(tf, inst) {
+        crate::pmu::fw_count(crate::pmu::FWEvent::IllegalInsn);
+        return;
+    }
+
+    super::redirect(mcause, mtval);
+}
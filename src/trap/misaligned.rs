@@ -0,0 +1,112 @@
+use super::mprv;
+use super::TrapFrame;
+
+struct Access {
+    width: usize,
+    signed: bool,
+    reg: usize,
+}
+
+fn decode_load(inst: u32) -> Option<Access> {
+    let inst = inst as usize;
+
+    if inst & 0b11 == 0b11 {
+        if inst & 0x7F != 0x03 {
+            return None;
+        }
+
+        let (width, signed) = match (inst >> 12) & 7 {
+            0 => (1, true),
+            1 => (2, true),
+            2 => (4, true),
+            3 => (8, false),
+            4 => (1, false),
+            5 => (2, false),
+            6 => (4, false),
+            _ => return None,
+        };
+
+        return Some(Access { width, signed, reg: (inst >> 7) & 31 });
+    }
+
+    match (inst & 0b11, (inst >> 13) & 7) {
+        (0b00, 0b010) => Some(Access { width: 4, signed: true, reg: ((inst >> 2) & 7) + 8 }), // C.LW
+        (0b00, 0b011) => Some(Access { width: 8, signed: false, reg: ((inst >> 2) & 7) + 8 }), // C.LD
+        (0b10, 0b010) => Some(Access { width: 4, signed: true, reg: (inst >> 7) & 31 }), // C.LWSP
+        (0b10, 0b011) => Some(Access { width: 8, signed: false, reg: (inst >> 7) & 31 }), // C.LDSP
+        _ => None,
+    }
+}
+
+fn decode_store(inst: u32) -> Option<Access> {
+    let inst = inst as usize;
+
+    if inst & 0b11 == 0b11 {
+        if inst & 0x7F != 0x23 {
+            return None;
+        }
+
+        let width = match (inst >> 12) & 7 {
+            0 => 1,
+            1 => 2,
+            2 => 4,
+            3 => 8,
+            _ => return None,
+        };
+
+        return Some(Access { width, signed: false, reg: (inst >> 20) & 31 });
+    }
+
+    match (inst & 0b11, (inst >> 13) & 7) {
+        (0b00, 0b110) => Some(Access { width: 4, signed: false, reg: ((inst >> 2) & 7) + 8 }), // C.SW
+        (0b00, 0b111) => Some(Access { width: 8, signed: false, reg: ((inst >> 2) & 7) + 8 }), // C.SD
+        (0b10, 0b110) => Some(Access { width: 4, signed: false, reg: (inst >> 2) & 31 }), // C.SWSP
+        (0b10, 0b111) => Some(Access { width: 8, signed: false, reg: (inst >> 2) & 31 }), // C.SDSP
+        _ => None,
+    }
+}
+
+// Emulates a misaligned load or store, the address is taken from mtval
+pub fn handle(tf: &mut TrapFrame, mcause: usize, addr: usize, store: bool) {
+    let mepc = riscv::register::mepc::read();
+
+    let (inst, len) = match mprv::fetch_inst(mepc) {
+        Ok(r) => r,
+        Err((cause, tval)) => return super::redirect(cause, tval),
+    };
+
+    let access = if store { decode_store(inst) } else { decode_load(inst) };
+    let access = match access {
+        Some(a) => a,
+        // Floating point accesses are left to S-mode
+        None => return super::redirect(mcause, addr),
+    };
+
+    if store {
+        let val = tf.get(access.reg);
+        for i in 0..access.width {
+            if let Err((cause, tval)) = mprv::store_u8(addr + i, (val >> (i * 8)) as u8) {
+                return super::redirect(cause, tval);
+            }
+        }
+        crate::pmu::fw_count(crate::pmu::FWEvent::MisalignedStore);
+    } else {
+        let mut val = 0usize;
+        for i in 0..access.width {
+            match mprv::load_u8(addr + i) {
+                Ok(b) => val |= (b as usize) << (i * 8),
+                Err((cause, tval)) => return super::redirect(cause, tval),
+            }
+        }
+
+        if access.signed && access.width < 8 {
+            let shift = (8 - access.width) * 8;
+            val = (((val << shift) as isize) >> shift) as usize;
+        }
+
+        tf.set(access.reg, val);
+        crate::pmu::fw_count(crate::pmu::FWEvent::MisalignedLoad);
+    }
+
+    riscv::register::mepc::write(mepc + len);
+}
@@ -1,5 +1,11 @@
 use riscv;
 
+mod amo;
+mod illegal;
+#[cfg(feature = "emulate-misaligned")]
+mod misaligned;
+pub mod mprv;
+
 /**
  * Trap frame
  * we are only going to save registers, because we will keep supervisor state CSR unchanged
@@ -8,6 +14,34 @@ pub struct TrapFrame {
     pub reg: [usize; 32],
 }
 
+impl TrapFrame {
+    // x0 is not saved in the frame
+    pub fn get(&self, r: usize) -> usize {
+        if r == 0 {
+            0
+        } else {
+            self.reg[r]
+        }
+    }
+
+    pub fn set(&mut self, r: usize, val: usize) {
+        if r != 0 {
+            self.reg[r] = val;
+        }
+    }
+}
+
+/**
+ * Installs mtvec as early as possible, so that mprv::guarded can catch faults before setup
+ * mscratch = 0 marks that the trap comes from M-mode, and its reset value is unspecified
+ */
+pub fn early_setup() {
+    unsafe {
+        llvm_asm!("csrw mscratch, zero" :::: "volatile");
+        riscv::register::mtvec::write(trap_enter as usize, riscv::register::mtvec::TrapMode::Direct);
+    }
+}
+
 pub fn setup() {
     unsafe {
         // Setup MEDELEG, only handles S_CALL for now
@@ -21,17 +55,26 @@ pub fn setup() {
         // TODO: U-mode interrupts
 
         // Setup MEDELEG
-        let medeleg = 0xFFFF & !(1 << 9); // Delegate everything except S_CALL
+        let mut medeleg = 0xFFFF & !(1 << 9) & !(1 << 2); // Delegate everything except S_CALL and illegal instructions
+        if cfg!(feature = "emulate-misaligned") {
+            medeleg &= !((1 << 4) | (1 << 6)); // Load / store address misaligned
+        }
         llvm_asm!("csrw medeleg, $0" :: "r"(medeleg) :: "volatile");
 
+        // Allow S-mode to read all counters
+        let mcounteren: usize = 0xFFFF_FFFF;
+        llvm_asm!("csrw mcounteren, $0" :: "r"(mcounteren) :: "volatile");
+
         // Setup MTVEC
         riscv::register::mtvec::write(
             trap_enter as usize,
             riscv::register::mtvec::TrapMode::Direct,
         );
 
+        crate::mem::local_data().sstc = setup_sstc();
+
         // Set corresponding MIE
-        riscv::register::mie::set_mext();
+        // External interrupts are routed to S-mode through the PLIC
         riscv::register::mie::set_msoft();
         // riscv::register::mie::set_mtimer();
         // Mtimer gets enabled on first set_timer
@@ -41,6 +84,23 @@ pub fn setup() {
     }
 }
 
+const CSR_MENVCFG_STCE: usize = 1 << 63;
+
+/**
+ * Lets S-mode program stimecmp directly if the hart has Sstc, returns whether it's enabled
+ *
+ * menvcfg is absent before priv 1.12, and STCE is read-only zero without Sstc.
+ */
+fn setup_sstc() -> bool {
+    let stce = mprv::guarded(|| unsafe {
+        let menvcfg: usize;
+        llvm_asm!("csrs 0x30A, $1; csrr $0, 0x30A" : "=r"(menvcfg) : "r"(CSR_MENVCFG_STCE) :: "volatile");
+        menvcfg
+    });
+
+    stce.map(|menvcfg| menvcfg & CSR_MENVCFG_STCE != 0).unwrap_or(false)
+}
+
 macro_rules! op_reg {
     ($op: literal, $cnt:literal) => {
         llvm_asm!(concat!(
@@ -176,7 +236,7 @@ unsafe fn trap_ret() -> ! {
 }
 
 #[naked]
-pub unsafe fn next_ret(hartid: usize, fdt_addr: *const u8) -> ! {
+pub unsafe fn next_ret(hartid: usize, arg1: usize) -> ! {
     llvm_asm!("mv s0, sp");
     llvm_asm!(concat!("addi sp, sp, -", stringify!(32 * 8)));
     llvm_asm!("1: addi s0, s0, -8");
@@ -184,7 +244,7 @@ pub unsafe fn next_ret(hartid: usize, fdt_addr: *const u8) -> ! {
     llvm_asm!("bne sp, s0, 1b");
 
     llvm_asm!("sd $0, 8*10(sp)" :: "r"(hartid) :: "volatile");
-    llvm_asm!("sd $0, 8*11(sp)" :: "r"(fdt_addr) :: "volatile");
+    llvm_asm!("sd $0, 8*11(sp)" :: "r"(arg1) :: "volatile");
 
     trap_ret();
 }
@@ -194,15 +254,21 @@ pub unsafe fn next_ret(hartid: usize, fdt_addr: *const u8) -> ! {
 pub extern "C" fn wrapped_trap<'a>(tf: &'a mut TrapFrame) {
     use riscv::register::mcause::{read, Exception, Interrupt, Trap};
     let mcause = read();
+    let mtval = riscv::register::mtval::read();
 
     // crate::mprintln!("[MeowSBI] trap: {:?}", mcause.cause()).unwrap();
 
+    if mcause.is_exception() && mprv::catch_fault(mcause.bits(), mtval) {
+        return;
+    }
+
     match mcause.cause() {
         Trap::Exception(Exception::SupervisorEnvCall) => {
+            crate::pmu::fw_count(crate::pmu::FWEvent::SBICall);
             let ret = crate::sbi::call(
                 tf.reg[17], // a7
                 tf.reg[16], // a6
-                tf.reg[10], tf.reg[11], tf.reg[12],
+                tf.reg[10], tf.reg[11], tf.reg[12], tf.reg[13], tf.reg[14],
             );
 
             if ret.error == crate::sbi::SBIErr::Legacy {
@@ -226,11 +292,58 @@ pub extern "C" fn wrapped_trap<'a>(tf: &'a mut TrapFrame) {
         Trap::Interrupt(Interrupt::MachineSoft) => {
             crate::mem::local_data().ipi_handle();
         }
+        Trap::Exception(Exception::IllegalInstruction) => {
+            illegal::handle(tf, mcause.bits(), mtval);
+        }
+        #[cfg(feature = "emulate-misaligned")]
+        Trap::Exception(Exception::LoadMisaligned) => {
+            misaligned::handle(tf, mcause.bits(), mtval, false);
+        }
+        #[cfg(feature = "emulate-misaligned")]
+        Trap::Exception(Exception::StoreMisaligned) => {
+            misaligned::handle(tf, mcause.bits(), mtval, true);
+        }
         t => {
             crate::mprintln!("Unexpected trap: {:?}", t).unwrap();
             crate::mprintln!("MEPC:  0x{:016X}", riscv::register::mepc::read()).unwrap();
-            crate::mprintln!("MTVAL: 0x{:016X}", riscv::register::mtval::read()).unwrap();
+            crate::mprintln!("MTVAL: 0x{:016X}", mtval).unwrap();
             panic!();
         }
     }
 }
+
+/**
+ * Forwards an exception to S-mode, as if it's delegated through medeleg
+ * Only valid for traps from S/U-mode
+ */
+pub fn redirect(cause: usize, tval: usize) {
+    use riscv::register::mstatus;
+
+    let status = mstatus::read();
+    if status.mpp() == mstatus::MPP::Machine {
+        crate::mprintln!("Cannot redirect trap {} from M-mode", cause).unwrap();
+        panic!();
+    }
+
+    let mepc = riscv::register::mepc::read();
+    let stvec = riscv::register::stvec::read().address();
+
+    unsafe {
+        llvm_asm!("csrw sepc, $0" :: "r"(mepc) :: "volatile");
+        llvm_asm!("csrw scause, $0" :: "r"(cause) :: "volatile");
+        llvm_asm!("csrw stval, $0" :: "r"(tval) :: "volatile");
+
+        // SPP = previous mode, SPIE = SIE, SIE = 0
+        let mut bits: usize;
+        llvm_asm!("csrr $0, mstatus" : "=r"(bits) ::: "volatile");
+        let spp = (status.mpp() == mstatus::MPP::Supervisor) as usize;
+        let sie = status.sie() as usize;
+        bits &= !((1 << 8) | (1 << 5) | (1 << 1));
+        bits |= (spp << 8) | (sie << 5);
+        llvm_asm!("csrw mstatus, $0" :: "r"(bits) :: "volatile");
+
+        mstatus::set_mpp(mstatus::MPP::Supervisor);
+    }
+
+    riscv::register::mepc::write(stvec);
+}
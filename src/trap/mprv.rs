@@ -0,0 +1,100 @@
+/**
+ * Memory accesses on behalf of the trapping context, through MPRV
+ *
+ * Faults raised by these accesses are caught by wrapped_trap, which records the cause and skips
+ * the faulting instruction. We then report the fault as Err((mcause, mtval)), so that the caller
+ * can redirect it to S-mode.
+ */
+
+pub type Fault = (usize, usize);
+
+// $bits are set in mstatus during the access: MPRV, plus MXR for instruction fetches
+macro_rules! mprv_load {
+    ($name:ident, $ty:ty, $inst:literal, $bits:literal) => {
+        pub fn $name(addr: usize) -> Result<$ty, Fault> {
+            let val: usize;
+            guarded(|| unsafe {
+                llvm_asm!(concat!(r#"
+                .option push
+                .option norvc
+                li t0, "#, $bits, r#"
+                csrrs t0, mstatus, t0
+                "#, $inst, r#" $0, 0($1)
+                csrw mstatus, t0
+                .option pop
+                "#) : "=&r"(val) : "r"(addr) : "t0", "memory" : "volatile");
+                val as $ty
+            })
+        }
+    };
+}
+
+macro_rules! mprv_store {
+    ($name:ident, $ty:ty, $inst:literal) => {
+        pub fn $name(addr: usize, val: $ty) -> Result<(), Fault> {
+            guarded(|| unsafe {
+                llvm_asm!(concat!(r#"
+                .option push
+                .option norvc
+                li t0, (1<<17)
+                csrrs t0, mstatus, t0
+                "#, $inst, r#" $1, 0($0)
+                csrw mstatus, t0
+                .option pop
+                "#) :: "r"(addr), "r"(val as usize) : "t0", "memory" : "volatile");
+            })
+        }
+    };
+}
+
+mprv_load!(load_u8, u8, "lbu", "(1<<17)");
+mprv_load!(load_usize, usize, "ld", "(1<<17)");
+// Instructions may live in execute-only pages
+mprv_load!(fetch_u16, u16, "lhu", "(1<<17) | (1<<19)");
+mprv_store!(store_u8, u8, "sb");
+
+// Nested traps overwrite mepc, so it's saved and restored here
+pub fn guarded<T, F: FnOnce() -> T>(f: F) -> Result<T, Fault> {
+    let data = crate::mem::local_data();
+    let mepc = riscv::register::mepc::read();
+
+    data.mprv_fault = None;
+    data.mprv_faulted = 0;
+    data.mprv_active = true;
+    let ret = f();
+    data.mprv_active = false;
+
+    riscv::register::mepc::write(mepc);
+
+    match data.mprv_fault.take() {
+        Some(fault) => Err(fault),
+        None => Ok(ret),
+    }
+}
+
+// Called by wrapped_trap on exceptions raised inside M-mode
+pub fn catch_fault(cause: usize, tval: usize) -> bool {
+    let data = crate::mem::local_data();
+    if !data.mprv_active {
+        return false;
+    }
+
+    data.mprv_fault = Some((cause, tval));
+    data.mprv_faulted = 1;
+
+    // Accessors are assembled with norvc, and LR/SC / CSR accesses have no compressed forms
+    let mepc = riscv::register::mepc::read();
+    riscv::register::mepc::write(mepc + 4);
+    true
+}
+
+// Fetches the instruction at the given address, returns the instruction and its length
+pub fn fetch_inst(addr: usize) -> Result<(u32, usize), Fault> {
+    let low = fetch_u16(addr)? as u32;
+    if low & 0b11 != 0b11 {
+        return Ok((low, 2));
+    }
+
+    let high = fetch_u16(addr + 2)? as u32;
+    Ok((low | (high << 16), 4))
+}
@@ -28,6 +28,10 @@ impl CLINT {
         self.clear_soft();
     }
 
+    pub fn read_time(&self) -> u64 {
+        unsafe { core::ptr::read_volatile(self.base.offset(0xbff8) as *const u64) }
+    }
+
     pub fn set_timer(&self, instant: u64) {
         unsafe {
             core::ptr::write_volatile(
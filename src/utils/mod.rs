@@ -1,2 +1,5 @@
 pub mod clint;
+pub mod plic;
+pub mod sifive_test;
+pub mod syscon;
 pub mod uart;
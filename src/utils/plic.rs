@@ -0,0 +1,72 @@
+pub struct PLIC {
+    base: *mut u8,
+    ndev: usize,
+    m_ctx: Option<usize>,
+    s_ctx: Option<usize>,
+}
+
+mod offsets {
+    pub const PRIORITY: isize = 0x0;
+    pub const ENABLE: isize = 0x2000;
+    pub const ENABLE_STRIDE: isize = 0x80;
+    pub const CONTEXT: isize = 0x200000;
+    pub const CONTEXT_STRIDE: isize = 0x1000;
+    pub const THRESHOLD: isize = 0x0;
+}
+
+const MAX_THRESHOLD: u32 = 7;
+
+impl PLIC {
+    pub fn new(base: *mut u8, ndev: usize, m_ctx: Option<usize>, s_ctx: Option<usize>) -> PLIC {
+        PLIC { base, ndev, m_ctx, s_ctx }
+    }
+
+    pub fn setup_leader(&self) {
+        // All sources stay masked until S-mode enables them
+        for src in 1..=self.ndev {
+            unsafe {
+                core::ptr::write_volatile(
+                    (self.base.offset(offsets::PRIORITY) as *mut u32).offset(src as isize),
+                    0,
+                );
+            }
+        }
+    }
+
+    pub fn setup(&self) {
+        // M-mode does not take external interrupts
+        if let Some(ctx) = self.m_ctx {
+            self.clear_enable(ctx);
+            self.set_threshold(ctx, MAX_THRESHOLD);
+        }
+
+        // S-mode claims and completes on its own context directly
+        if let Some(ctx) = self.s_ctx {
+            self.clear_enable(ctx);
+            self.set_threshold(ctx, 0);
+        }
+    }
+
+    fn clear_enable(&self, ctx: usize) {
+        let words = (self.ndev + 1 + 31) / 32;
+        for i in 0..words {
+            unsafe {
+                core::ptr::write_volatile(
+                    (self.base.offset(offsets::ENABLE + offsets::ENABLE_STRIDE * ctx as isize) as *mut u32)
+                        .offset(i as isize),
+                    0,
+                );
+            }
+        }
+    }
+
+    fn set_threshold(&self, ctx: usize, threshold: u32) {
+        unsafe {
+            core::ptr::write_volatile(
+                self.base.offset(offsets::CONTEXT + offsets::CONTEXT_STRIDE * ctx as isize + offsets::THRESHOLD)
+                    as *mut u32,
+                threshold,
+            );
+        }
+    }
+}
@@ -0,0 +1,28 @@
+pub struct SiFiveTest {
+    base: *mut u32,
+}
+
+mod codes {
+    pub const FAIL: u32 = 0x3333;
+    pub const PASS: u32 = 0x5555;
+    pub const RESET: u32 = 0x7777;
+}
+
+impl SiFiveTest {
+    pub fn new(base: *mut u8) -> SiFiveTest {
+        SiFiveTest { base: base as _ }
+    }
+
+    pub fn pass(&self) {
+        unsafe { core::ptr::write_volatile(self.base, codes::PASS) }
+    }
+
+    // QEMU exits with the given code
+    pub fn fail(&self, code: u16) {
+        unsafe { core::ptr::write_volatile(self.base, codes::FAIL | ((code as u32) << 16)) }
+    }
+
+    pub fn reset(&self) {
+        unsafe { core::ptr::write_volatile(self.base, codes::RESET) }
+    }
+}
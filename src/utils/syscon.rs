@@ -0,0 +1,26 @@
+/**
+ * A single register write into a syscon device,
+ * described by syscon-poweroff / syscon-reboot nodes
+ */
+pub struct SysConReg {
+    addr: *mut u32,
+    value: u32,
+    mask: u32,
+}
+
+impl SysConReg {
+    pub fn new(addr: *mut u8, value: u32, mask: u32) -> SysConReg {
+        SysConReg { addr: addr as _, value, mask }
+    }
+
+    pub fn fire(&self) {
+        unsafe {
+            let orig = if self.mask == core::u32::MAX {
+                0
+            } else {
+                core::ptr::read_volatile(self.addr)
+            };
+            core::ptr::write_volatile(self.addr, (orig & !self.mask) | (self.value & self.mask));
+        }
+    }
+}
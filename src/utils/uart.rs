@@ -77,4 +77,16 @@ impl UART16550 {
             core::ptr::read_volatile((self.base + (offsets::RBR << self.shift)) as *const u8)
         }
     }
+
+    pub fn try_getchar(&self) -> Option<u8> {
+        unsafe {
+            if core::ptr::read_volatile((self.base + (offsets::LSR << self.shift)) as *const u8) & masks::DR
+                == 0
+            {
+                return None;
+            }
+
+            Some(core::ptr::read_volatile((self.base + (offsets::RBR << self.shift)) as *const u8))
+        }
+    }
 }